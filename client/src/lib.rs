@@ -0,0 +1,411 @@
+use anchor_lang::{
+  prelude::*,
+  solana_program::{instruction::Instruction, sysvar::instructions::ID as INSTRUCTIONS_SYSVAR_ID},
+  AnchorDeserialize,
+  InstructionData,
+  ToAccountMetas,
+};
+use anchor_spl::associated_token::get_associated_token_address_with_program_id;
+use blueshift_anchor_flash_loan::{
+  accounts,
+  fees::{loan_fee, total_due, Rounding},
+  instruction,
+  LoanQuote,
+  CONFIG_SEED,
+  LOAN_SEED,
+  LP_MINT_SEED,
+  POOL_SEED,
+  SOL_POOL_MINT,
+  SOL_VAULT_SEED,
+};
+
+pub use blueshift_anchor_flash_loan::ID as PROGRAM_ID;
+
+/// Address of the protocol config PDA
+pub fn find_config_pda() -> (Pubkey, u8) {
+  Pubkey::find_program_address(&[CONFIG_SEED], &PROGRAM_ID)
+}
+
+/// Address of the pool PDA lending `mint`
+pub fn find_pool_pda(mint: &Pubkey) -> (Pubkey, u8) {
+  Pubkey::find_program_address(&[POOL_SEED, mint.as_ref()], &PROGRAM_ID)
+}
+
+/// Address of the LP share mint of `pool`
+pub fn find_lp_mint_pda(pool: &Pubkey) -> (Pubkey, u8) {
+  Pubkey::find_program_address(&[LP_MINT_SEED, pool.as_ref()], &PROGRAM_ID)
+}
+
+/// Address of the loan receipt PDA of `borrower` on `pool`
+pub fn find_loan_pda(pool: &Pubkey, borrower: &Pubkey) -> (Pubkey, u8) {
+  Pubkey::find_program_address(&[LOAN_SEED, pool.as_ref(), borrower.as_ref()], &PROGRAM_ID)
+}
+
+/// Address of the vault holding the liquidity of the pool lending `mint`
+///
+/// `token_program` is the owner of `mint`, SPL Token or Token-2022.
+pub fn find_vault_address(mint: &Pubkey, token_program: &Pubkey) -> Pubkey {
+  get_associated_token_address_with_program_id(&find_pool_pda(mint).0, mint, token_program)
+}
+
+/// Address of the SOL pool PDA
+pub fn find_sol_pool_pda() -> (Pubkey, u8) {
+  find_pool_pda(&SOL_POOL_MINT)
+}
+
+/// Address of the vault PDA holding the lamports of the SOL pool
+pub fn find_sol_vault_pda() -> (Pubkey, u8) {
+  Pubkey::find_program_address(&[SOL_VAULT_SEED, find_sol_pool_pda().0.as_ref()], &PROGRAM_ID)
+}
+
+/// Accounts of a `borrow` of `borrower` on the pool lending `mint`
+pub fn borrow_accounts(borrower: &Pubkey, mint: &Pubkey, token_program: &Pubkey) -> Vec<AccountMeta> {
+  let pool = find_pool_pda(mint).0;
+
+  accounts::Borrow {
+    borrower: *borrower,
+    pool,
+    mint: *mint,
+    borrower_ata: get_associated_token_address_with_program_id(borrower, mint, token_program),
+    vault: get_associated_token_address_with_program_id(&pool, mint, token_program),
+    config: find_config_pda().0,
+    loan: find_loan_pda(&pool, borrower).0,
+    instructions: INSTRUCTIONS_SYSVAR_ID,
+    token_program: *token_program,
+    associated_token_program: anchor_spl::associated_token::ID,
+    system_program: anchor_lang::system_program::ID,
+  }
+  .to_account_metas(None)
+}
+
+/// Accounts of a `repay` of `borrower` on the pool lending `mint`
+pub fn repay_accounts(borrower: &Pubkey, mint: &Pubkey, token_program: &Pubkey) -> Vec<AccountMeta> {
+  let pool = find_pool_pda(mint).0;
+
+  accounts::Repay {
+    borrower: *borrower,
+    pool,
+    mint: *mint,
+    borrower_ata: get_associated_token_address_with_program_id(borrower, mint, token_program),
+    vault: get_associated_token_address_with_program_id(&pool, mint, token_program),
+    config: find_config_pda().0,
+    loan: find_loan_pda(&pool, borrower).0,
+    instructions: INSTRUCTIONS_SYSVAR_ID,
+    token_program: *token_program,
+  }
+  .to_account_metas(None)
+}
+
+/// `borrow` of `amount` of `mint` by `borrower`
+pub fn borrow_ix(borrower: &Pubkey, mint: &Pubkey, token_program: &Pubkey, amount: u64) -> Instruction {
+  Instruction {
+    program_id: PROGRAM_ID,
+    accounts: borrow_accounts(borrower, mint, token_program),
+    data: instruction::Borrow { borrow_amount: amount }.data(),
+  }
+}
+
+/// `repay` of the loan of `borrower` on the pool lending `mint`
+pub fn repay_ix(borrower: &Pubkey, mint: &Pubkey, token_program: &Pubkey) -> Instruction {
+  Instruction {
+    program_id: PROGRAM_ID,
+    accounts: repay_accounts(borrower, mint, token_program),
+    data: instruction::Repay {}.data(),
+  }
+}
+
+/// `flash_loan` of `amount` of `mint` to `receiver_token_account`, calling `on_flash_loan` on `receiver_program`
+///
+/// Accounts the receiver needs beyond the fixed ones are appended to the
+/// returned instruction accounts, they are forwarded to the callback in order.
+pub fn flash_loan_ix(
+  borrower: &Pubkey,
+  mint: &Pubkey,
+  token_program: &Pubkey,
+  receiver_program: &Pubkey,
+  receiver_token_account: &Pubkey,
+  amount: u64,
+  data: Vec<u8>,
+) -> Instruction {
+  Instruction {
+    program_id: PROGRAM_ID,
+    accounts: accounts::FlashLoan {
+      borrower: *borrower,
+      pool: find_pool_pda(mint).0,
+      mint: *mint,
+      vault: find_vault_address(mint, token_program),
+      receiver_token_account: *receiver_token_account,
+      config: find_config_pda().0,
+      receiver_program: *receiver_program,
+      token_program: *token_program,
+    }
+    .to_account_metas(None),
+    data: instruction::FlashLoan { amount, data }.data(),
+  }
+}
+
+/// Accounts of a `borrow_sol` of `borrower`
+pub fn borrow_sol_accounts(borrower: &Pubkey) -> Vec<AccountMeta> {
+  let pool = find_sol_pool_pda().0;
+
+  accounts::BorrowSol {
+    borrower: *borrower,
+    pool,
+    config: find_config_pda().0,
+    loan: find_loan_pda(&pool, borrower).0,
+    vault: find_sol_vault_pda().0,
+    instructions: INSTRUCTIONS_SYSVAR_ID,
+    system_program: anchor_lang::system_program::ID,
+  }
+  .to_account_metas(None)
+}
+
+/// Accounts of a `repay_sol` of `borrower`
+pub fn repay_sol_accounts(borrower: &Pubkey) -> Vec<AccountMeta> {
+  let pool = find_sol_pool_pda().0;
+
+  accounts::RepaySol {
+    borrower: *borrower,
+    pool,
+    config: find_config_pda().0,
+    loan: find_loan_pda(&pool, borrower).0,
+    vault: find_sol_vault_pda().0,
+    instructions: INSTRUCTIONS_SYSVAR_ID,
+    system_program: anchor_lang::system_program::ID,
+  }
+  .to_account_metas(None)
+}
+
+/// `borrow_sol` of `amount` lamports by `borrower`
+pub fn borrow_sol_ix(borrower: &Pubkey, amount: u64) -> Instruction {
+  Instruction {
+    program_id: PROGRAM_ID,
+    accounts: borrow_sol_accounts(borrower),
+    data: instruction::BorrowSol { borrow_amount: amount }.data(),
+  }
+}
+
+/// `repay_sol` of the SOL loan of `borrower`
+pub fn repay_sol_ix(borrower: &Pubkey) -> Instruction {
+  Instruction {
+    program_id: PROGRAM_ID,
+    accounts: repay_sol_accounts(borrower),
+    data: instruction::RepaySol {}.data(),
+  }
+}
+
+/// `check_repaid` of the loan receipts `receipts`, failing while any of them is open
+///
+/// Loans opened through a CPI must be followed by a top-level check of their receipt.
+pub fn check_repaid_ix(receipts: &[Pubkey]) -> Instruction {
+  let mut accounts = accounts::CheckRepaid { instructions: INSTRUCTIONS_SYSVAR_ID }.to_account_metas(None);
+  accounts.extend(receipts.iter().map(|receipt| AccountMeta::new_readonly(*receipt, false)));
+
+  Instruction {
+    program_id: PROGRAM_ID,
+    accounts,
+    data: instruction::CheckRepaid {}.data(),
+  }
+}
+
+/// Cost of a loan
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FeeQuote {
+  /// Fee charged on top of the principal
+  pub fee: u64,
+  /// Principal plus fee, taken from the borrower by `repay`
+  pub total: u64,
+}
+
+/// Quote the fee of a loan of `amount` on a pool charging `fee_bps`, with the pool `rounding` and `min_fee`
+pub fn quote_fee(amount: u64, fee_bps: u16, rounding: Rounding, min_fee: u64) -> Option<FeeQuote> {
+  let fee = loan_fee(amount, fee_bps, rounding, min_fee).ok()?;
+  let total = total_due(amount, fee_bps, rounding, min_fee).ok()?;
+
+  Some(FeeQuote { fee, total })
+}
+
+/// `quote` of a loan of `amount` on the pool lending `mint`
+pub fn quote_ix(mint: &Pubkey, token_program: &Pubkey, amount: u64) -> Instruction {
+  Instruction {
+    program_id: PROGRAM_ID,
+    accounts: accounts::Quote {
+      pool: find_pool_pda(mint).0,
+      mint: *mint,
+      vault: find_vault_address(mint, token_program),
+      token_program: *token_program,
+    }
+    .to_account_metas(None),
+    data: instruction::Quote { amount }.data(),
+  }
+}
+
+/// Quote a loan of `amount` on the pool lending `mint` by simulating a `quote`
+///
+/// Unlike [`quote_fee`] this reads the live pool: its fee, caps and liquidity,
+/// and the mint transfer fee. `simulate` runs the instruction (RPC
+/// `simulateTransaction`, LiteSVM, ...) and returns the program id and data of
+/// the transaction return data, if any. Returns `None` when the return data is
+/// missing or was not set by this program.
+pub fn simulate_quote<E>(
+  mint: &Pubkey,
+  token_program: &Pubkey,
+  amount: u64,
+  simulate: impl FnOnce(Instruction) -> std::result::Result<Option<(Pubkey, Vec<u8>)>, E>,
+) -> std::result::Result<Option<LoanQuote>, E> {
+  Ok(decode_quote(simulate(quote_ix(mint, token_program, amount))?))
+}
+
+/// `quote_sol` of a loan of `amount` lamports on the SOL pool
+pub fn quote_sol_ix(amount: u64) -> Instruction {
+  Instruction {
+    program_id: PROGRAM_ID,
+    accounts: accounts::QuoteSol {
+      pool: find_sol_pool_pda().0,
+      vault: find_sol_vault_pda().0,
+    }
+    .to_account_metas(None),
+    data: instruction::QuoteSol { amount }.data(),
+  }
+}
+
+/// Quote a loan of `amount` lamports on the SOL pool by simulating a `quote_sol`
+///
+/// Same as [`simulate_quote`] for the SOL pool.
+pub fn simulate_sol_quote<E>(
+  amount: u64,
+  simulate: impl FnOnce(Instruction) -> std::result::Result<Option<(Pubkey, Vec<u8>)>, E>,
+) -> std::result::Result<Option<LoanQuote>, E> {
+  Ok(decode_quote(simulate(quote_sol_ix(amount))?))
+}
+
+/// Quote in the return data, if this program set it
+fn decode_quote(return_data: Option<(Pubkey, Vec<u8>)>) -> Option<LoanQuote> {
+  return_data
+    .filter(|(program_id, _)| *program_id == PROGRAM_ID)
+    .and_then(|(_, data)| LoanQuote::try_from_slice(&data).ok())
+}
+
+/// Reasons a flash loan transaction cannot be built
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BuilderError {
+  /// No loan was added to the builder
+  NoLoan,
+  /// A loan of 0 was requested
+  ZeroAmount(Pubkey),
+  /// Two loans were requested on the same mint, which share one loan receipt PDA
+  DuplicateMint(Pubkey),
+}
+
+impl std::fmt::Display for BuilderError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      BuilderError::NoLoan => write!(f, "no loan was added to the transaction"),
+      BuilderError::ZeroAmount(mint) => write!(f, "loan of 0 requested on mint {}", mint),
+      BuilderError::DuplicateMint(mint) => write!(f, "several loans requested on mint {}", mint),
+    }
+  }
+}
+
+impl std::error::Error for BuilderError {}
+
+/// A loan added to a [`FlashLoanTxBuilder`]
+#[derive(Clone, Copy, Debug)]
+struct LoanRequest {
+  mint: Pubkey,
+  /// Owner of `mint`, `None` for a loan of lamports from the SOL pool
+  token_program: Option<Pubkey>,
+  amount: u64,
+}
+
+impl LoanRequest {
+  fn borrow_ix(&self, borrower: &Pubkey) -> Instruction {
+    match self.token_program {
+      Some(token_program) => borrow_ix(borrower, &self.mint, &token_program, self.amount),
+      None => borrow_sol_ix(borrower, self.amount),
+    }
+  }
+
+  fn repay_ix(&self, borrower: &Pubkey) -> Instruction {
+    match self.token_program {
+      Some(token_program) => repay_ix(borrower, &self.mint, &token_program),
+      None => repay_sol_ix(borrower),
+    }
+  }
+}
+
+/// Builds the instructions of a flash loan transaction
+///
+/// The output is laid out so that it passes the program introspection checks:
+/// leading instructions (compute budget, memo, ...), then every borrow, then the
+/// user instructions, then the repays in reverse order so each one settles its
+/// own borrow.
+#[derive(Clone, Debug)]
+pub struct FlashLoanTxBuilder {
+  borrower: Pubkey,
+  leading: Vec<Instruction>,
+  loans: Vec<LoanRequest>,
+  instructions: Vec<Instruction>,
+}
+
+impl FlashLoanTxBuilder {
+  pub fn new(borrower: Pubkey) -> Self {
+    Self {
+      borrower,
+      leading: Vec::new(),
+      loans: Vec::new(),
+      instructions: Vec::new(),
+    }
+  }
+
+  /// Add an instruction placed before the borrows, its program has to be allowed by the protocol config
+  pub fn leading_instruction(mut self, ix: Instruction) -> Self {
+    self.leading.push(ix);
+    self
+  }
+
+  /// Borrow `amount` of `mint`, owned by `token_program`, for the duration of the transaction
+  pub fn borrow(mut self, mint: Pubkey, token_program: Pubkey, amount: u64) -> Self {
+    self.loans.push(LoanRequest { mint, token_program: Some(token_program), amount });
+    self
+  }
+
+  /// Borrow `amount` lamports from the SOL pool for the duration of the transaction
+  pub fn borrow_sol(mut self, amount: u64) -> Self {
+    self.loans.push(LoanRequest { mint: SOL_POOL_MINT, token_program: None, amount });
+    self
+  }
+
+  /// Add an instruction running with the borrowed funds
+  pub fn instruction(mut self, ix: Instruction) -> Self {
+    self.instructions.push(ix);
+    self
+  }
+
+  /// Add several instructions running with the borrowed funds
+  pub fn instructions(mut self, ixs: impl IntoIterator<Item = Instruction>) -> Self {
+    self.instructions.extend(ixs);
+    self
+  }
+
+  pub fn build(self) -> std::result::Result<Vec<Instruction>, BuilderError> {
+    if self.loans.is_empty() {
+      return Err(BuilderError::NoLoan);
+    }
+    for (i, loan) in self.loans.iter().enumerate() {
+      if loan.amount == 0 {
+        return Err(BuilderError::ZeroAmount(loan.mint));
+      }
+      if self.loans[..i].iter().any(|other| other.mint == loan.mint) {
+        return Err(BuilderError::DuplicateMint(loan.mint));
+      }
+    }
+
+    let mut ixs = self.leading;
+    ixs.extend(self.loans.iter().map(|loan| loan.borrow_ix(&self.borrower)));
+    ixs.extend(self.instructions);
+    ixs.extend(self.loans.iter().rev().map(|loan| loan.repay_ix(&self.borrower)));
+
+    Ok(ixs)
+  }
+}
@@ -0,0 +1,321 @@
+// Client tests: the built transactions have to pass the program introspection checks
+
+#[cfg(test)]
+mod tests {
+    use anchor_lang::prelude::Pubkey;
+    use anchor_lang::InstructionData;
+    use anchor_lang::solana_program::instruction::Instruction;
+    use anchor_lang::solana_program::sysvar::instructions::{
+        construct_instructions_data, BorrowedAccountMeta, BorrowedInstruction,
+    };
+    use anchor_spl::associated_token::get_associated_token_address_with_program_id;
+    use anchor_spl::{token::ID as TOKEN_PROGRAM_ID, token_2022::ID as TOKEN_2022_PROGRAM_ID};
+    use blueshift_anchor_flash_loan::introspection::{
+        account_key, check_leading_instructions, is_borrow, pair_loans, InstructionsSysvar,
+        BORROWER_ATA_INDEX, COMPUTE_BUDGET_PROGRAM_ID, MEMO_PROGRAM_ID, SOL_LOAN_INDEX, VAULT_INDEX,
+    };
+    use blueshift_anchor_flash_loan::fees::Rounding;
+    use blueshift_anchor_flash_loan::{instruction, LoanQuote, SOL_POOL_MINT};
+    use blueshift_flash_loan_client::{
+        borrow_ix, borrow_sol_ix, check_repaid_ix, find_config_pda, flash_loan_ix, find_loan_pda, find_pool_pda,
+        find_sol_pool_pda, find_sol_vault_pda, find_vault_address, quote_fee, quote_ix, quote_sol_ix, repay_ix,
+        repay_sol_ix, simulate_quote, simulate_sol_quote, BuilderError, FeeQuote, FlashLoanTxBuilder, PROGRAM_ID,
+    };
+
+    /// Serialize instructions like the runtime does, with `current_index` as the executing instruction
+    fn sysvar_bytes(ixs: &[Instruction], current_index: u16) -> Vec<u8> {
+        let borrowed: Vec<BorrowedInstruction> = ixs
+            .iter()
+            .map(|ix| BorrowedInstruction {
+                program_id: &ix.program_id,
+                accounts: ix
+                    .accounts
+                    .iter()
+                    .map(|meta| BorrowedAccountMeta {
+                        pubkey: &meta.pubkey,
+                        is_signer: meta.is_signer,
+                        is_writable: meta.is_writable,
+                    })
+                    .collect(),
+                data: &ix.data,
+            })
+            .collect();
+        let mut data = construct_instructions_data(&borrowed);
+        let len = data.len();
+        data[len - 2..].copy_from_slice(&current_index.to_le_bytes());
+        data
+    }
+
+    fn user_ix() -> Instruction {
+        Instruction {
+            program_id: Pubkey::new_unique(),
+            accounts: vec![],
+            data: vec![7],
+        }
+    }
+
+    /// Test the loan instructions accounts
+    #[test]
+    fn test_loan_instruction_accounts() {
+        println!("🚀 Testing Loan Instruction Accounts");
+
+        let borrower = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let (pool, _) = find_pool_pda(&mint);
+
+        let borrow = borrow_ix(&borrower, &mint, &TOKEN_PROGRAM_ID, 1_000);
+        let repay = repay_ix(&borrower, &mint, &TOKEN_PROGRAM_ID);
+        // The repay only drops the programs creating the borrower ATA and loan receipt
+        assert_eq!(borrow.accounts[..repay.accounts.len()], repay.accounts[..], "Repay accounts should line up with borrow ones");
+        assert_eq!(borrow.accounts.len(), repay.accounts.len() + 2);
+        assert!(!repay.accounts.iter().any(|meta| meta.pubkey == anchor_spl::associated_token::ID));
+        assert!(is_borrow(&borrow));
+        assert_eq!(borrow.data, instruction::Borrow { borrow_amount: 1_000 }.data());
+
+        // The program checks these positions when pairing borrows with repays
+        assert_eq!(
+            account_key(&repay, BORROWER_ATA_INDEX),
+            Some(get_associated_token_address_with_program_id(&borrower, &mint, &TOKEN_PROGRAM_ID))
+        );
+        assert_eq!(account_key(&repay, VAULT_INDEX), Some(find_vault_address(&mint, &TOKEN_PROGRAM_ID)));
+
+        let keys: Vec<Pubkey> = borrow.accounts.iter().map(|meta| meta.pubkey).collect();
+        assert_eq!(keys[0], borrower);
+        assert!(borrow.accounts[0].is_signer, "Borrower should sign");
+        assert_eq!(keys[1], pool);
+        assert!(keys.contains(&find_config_pda().0));
+        assert!(keys.contains(&find_loan_pda(&pool, &borrower).0));
+        assert!(keys.contains(&TOKEN_PROGRAM_ID));
+
+        // Token-2022 mints have their ATAs derived with the Token-2022 program
+        let repay_2022 = repay_ix(&borrower, &mint, &TOKEN_2022_PROGRAM_ID);
+        assert_eq!(account_key(&repay_2022, VAULT_INDEX), Some(find_vault_address(&mint, &TOKEN_2022_PROGRAM_ID)));
+        assert_ne!(find_vault_address(&mint, &TOKEN_2022_PROGRAM_ID), find_vault_address(&mint, &TOKEN_PROGRAM_ID));
+        assert!(repay_2022.accounts.iter().any(|meta| meta.pubkey == TOKEN_2022_PROGRAM_ID));
+
+        println!("✅ Loan instruction accounts test passed");
+    }
+
+    /// Test that built transactions pass the introspection checks
+    #[test]
+    fn test_builder_passes_introspection() {
+        println!("🚀 Testing Flash Loan Builder");
+
+        let borrower = Pubkey::new_unique();
+        let (usdc, sol) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let compute_budget = Instruction { program_id: COMPUTE_BUDGET_PROGRAM_ID, accounts: vec![], data: vec![2] };
+        let memo = Instruction { program_id: MEMO_PROGRAM_ID, accounts: vec![], data: b"arb".to_vec() };
+
+        let ixs = FlashLoanTxBuilder::new(borrower)
+            .leading_instruction(compute_budget)
+            .leading_instruction(memo)
+            .borrow(usdc, TOKEN_PROGRAM_ID, 1_000_000)
+            .borrow(sol, TOKEN_2022_PROGRAM_ID, 5_000)
+            .instruction(user_ix())
+            .instructions(vec![user_ix(), user_ix()])
+            .build()
+            .unwrap();
+        assert_eq!(ixs.len(), 9);
+
+        // Every borrow and repay instruction sees a valid transaction
+        let allowed = [COMPUTE_BUDGET_PROGRAM_ID, MEMO_PROGRAM_ID];
+        for current in [2u16, 3, 7, 8] {
+            let data = sysvar_bytes(&ixs, current);
+            let sysvar = InstructionsSysvar::new(&data).unwrap();
+            let pairs = pair_loans(&sysvar).unwrap();
+            assert_eq!(pairs.len(), 2);
+
+            let first_borrow = pairs.iter().map(|pair| pair.borrow_index).min().unwrap();
+            assert!(check_leading_instructions(&sysvar, first_borrow, &allowed).is_ok());
+
+            for pair in &pairs {
+                assert_eq!(account_key(&pair.borrow, BORROWER_ATA_INDEX), account_key(&pair.repay, BORROWER_ATA_INDEX));
+                assert_eq!(account_key(&pair.borrow, VAULT_INDEX), account_key(&pair.repay, VAULT_INDEX));
+            }
+        }
+
+        // Loans unwind innermost first
+        let data = sysvar_bytes(&ixs, 2);
+        let sysvar = InstructionsSysvar::new(&data).unwrap();
+        let pairs: Vec<(usize, usize)> = pair_loans(&sysvar).unwrap().iter().map(|p| (p.borrow_index, p.repay_index)).collect();
+        assert_eq!(pairs, vec![(3, 7), (2, 8)]);
+
+        println!("✅ Flash loan builder test passed");
+    }
+
+    /// Test the callback flash loan instruction
+    #[test]
+    fn test_flash_loan_instruction() {
+        println!("🚀 Testing Flash Loan Instruction");
+
+        let borrower = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let (receiver, receiver_token_account) = (Pubkey::new_unique(), Pubkey::new_unique());
+
+        let ix = flash_loan_ix(&borrower, &mint, &TOKEN_PROGRAM_ID, &receiver, &receiver_token_account, 1_000, b"swap".to_vec());
+        let keys: Vec<Pubkey> = ix.accounts.iter().map(|meta| meta.pubkey).collect();
+        assert_eq!(
+            keys,
+            vec![
+                borrower,
+                find_pool_pda(&mint).0,
+                mint,
+                find_vault_address(&mint, &TOKEN_PROGRAM_ID),
+                receiver_token_account,
+                find_config_pda().0,
+                receiver,
+                TOKEN_PROGRAM_ID,
+            ]
+        );
+        assert!(ix.accounts[0].is_signer, "Borrower should sign");
+
+        // Callback loans are not paired by the introspection checks
+        assert!(!is_borrow(&ix));
+
+        println!("✅ Flash loan instruction test passed");
+    }
+
+    /// Test SOL loans, alone and next to token loans
+    #[test]
+    fn test_sol_loans() {
+        println!("🚀 Testing SOL Loans");
+
+        let borrower = Pubkey::new_unique();
+        let usdc = Pubkey::new_unique();
+        let (pool, _) = find_sol_pool_pda();
+        assert_eq!(pool, find_pool_pda(&SOL_POOL_MINT).0);
+
+        let borrow = borrow_sol_ix(&borrower, 1_000);
+        let repay = repay_sol_ix(&borrower);
+        assert_eq!(borrow.accounts, repay.accounts, "Borrow and repay should use the same accounts");
+        assert_eq!(account_key(&repay, SOL_LOAN_INDEX), Some(find_loan_pda(&pool, &borrower).0));
+        assert_eq!(account_key(&repay, VAULT_INDEX), Some(find_sol_vault_pda().0));
+
+        let ixs = FlashLoanTxBuilder::new(borrower)
+            .borrow_sol(1_000_000_000)
+            .borrow(usdc, TOKEN_PROGRAM_ID, 1_000)
+            .instruction(user_ix())
+            .build()
+            .unwrap();
+        assert_eq!(ixs.len(), 5);
+
+        let data = sysvar_bytes(&ixs, 0);
+        let sysvar = InstructionsSysvar::new(&data).unwrap();
+        let pairs: Vec<(usize, usize)> = pair_loans(&sysvar).unwrap().iter().map(|p| (p.borrow_index, p.repay_index)).collect();
+        assert_eq!(pairs, vec![(1, 3), (0, 4)]);
+        assert_eq!(ixs[0].accounts, borrow_sol_ix(&borrower, 1_000_000_000).accounts);
+        assert_eq!(ixs[4].data, repay.data);
+
+        assert_eq!(
+            FlashLoanTxBuilder::new(borrower).borrow_sol(1).borrow_sol(2).build().unwrap_err(),
+            BuilderError::DuplicateMint(SOL_POOL_MINT)
+        );
+
+        println!("✅ SOL loans test passed");
+    }
+
+    /// Test builder misuse
+    #[test]
+    fn test_builder_errors() {
+        println!("🚀 Testing Flash Loan Builder Errors");
+
+        let borrower = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+
+        assert_eq!(FlashLoanTxBuilder::new(borrower).instruction(user_ix()).build().unwrap_err(), BuilderError::NoLoan);
+        assert_eq!(FlashLoanTxBuilder::new(borrower).borrow(mint, TOKEN_PROGRAM_ID, 0).build().unwrap_err(), BuilderError::ZeroAmount(mint));
+        assert_eq!(
+            FlashLoanTxBuilder::new(borrower).borrow(mint, TOKEN_PROGRAM_ID, 1).borrow(mint, TOKEN_PROGRAM_ID, 2).build().unwrap_err(),
+            BuilderError::DuplicateMint(mint)
+        );
+
+        println!("✅ Flash loan builder errors test passed");
+    }
+
+    /// Test the check closing CPI loans
+    #[test]
+    fn test_check_repaid_ix() {
+        use anchor_lang::Discriminator;
+        use blueshift_anchor_flash_loan::introspection::{find_repay_check, is_repay_check};
+
+        println!("🚀 Testing Repay Check Instruction");
+
+        let integrator = Pubkey::new_unique();
+        let receipts = [find_loan_pda(&find_pool_pda(&Pubkey::new_unique()).0, &integrator).0, Pubkey::new_unique()];
+        let ix = check_repaid_ix(&receipts);
+        assert_eq!(&ix.data[..8], instruction::CheckRepaid::DISCRIMINATOR);
+        assert!(is_repay_check(&ix));
+        assert_eq!(ix.accounts.len(), 3, "The instructions sysvar comes before the receipts");
+        assert!(ix.accounts.iter().all(|meta| !meta.is_signer && !meta.is_writable), "Receipts are only read");
+
+        // A CPI loan from the first instruction finds the check of its receipt
+        let caller = Instruction { program_id: integrator, accounts: vec![], data: vec![] };
+        let data = sysvar_bytes(&[caller, ix], 0);
+        let sysvar = InstructionsSysvar::new(&data).unwrap();
+        assert_eq!(find_repay_check(&sysvar, 0, &receipts[0]).unwrap(), 1);
+        assert_eq!(find_repay_check(&sysvar, 0, &receipts[1]).unwrap(), 1);
+
+        println!("✅ Repay check instruction test passed");
+    }
+
+    /// Test fee quotes
+    #[test]
+    fn test_quote_fee() {
+        println!("🚀 Testing Fee Quotes");
+
+        assert_eq!(quote_fee(100_000, 500, Rounding::Up, 0), Some(FeeQuote { fee: 5_000, total: 105_000 }));
+        assert_eq!(quote_fee(999_999, 500, Rounding::Up, 0), Some(FeeQuote { fee: 50_000, total: 1_049_999 }));
+        assert_eq!(quote_fee(999_999, 500, Rounding::Down, 0), Some(FeeQuote { fee: 49_999, total: 1_049_998 }));
+        assert_eq!(quote_fee(1_000, 0, Rounding::Up, 0), Some(FeeQuote { fee: 0, total: 1_000 }));
+        assert_eq!(quote_fee(1_000, 0, Rounding::Up, 25), Some(FeeQuote { fee: 25, total: 1_025 }), "The minimum fee applies");
+        assert_eq!(quote_fee(u64::MAX, 10_000, Rounding::Up, 0), None, "Totals above u64::MAX cannot be repaid");
+
+        println!("✅ Fee quotes test passed");
+    }
+
+    /// Test quotes read from the `quote` return data
+    #[test]
+    fn test_simulate_quote() {
+        use anchor_lang::{AnchorSerialize, Discriminator};
+
+        println!("🚀 Testing Simulated Quotes");
+
+        let mint = Pubkey::new_unique();
+        let ix = quote_ix(&mint, &TOKEN_PROGRAM_ID, 1_000);
+        assert_eq!(&ix.data[..8], instruction::Quote::DISCRIMINATOR);
+        assert_eq!(u64::from_le_bytes(ix.data[8..16].try_into().unwrap()), 1_000);
+        assert_eq!(account_key(&ix, 0), Some(find_pool_pda(&mint).0));
+        assert_eq!(account_key(&ix, 2), Some(find_vault_address(&mint, &TOKEN_PROGRAM_ID)));
+
+        // The simulator gets the quote instruction and hands back the return data
+        let quote = LoanQuote { fee: 50, total: 1_050, available: 1_000_000 };
+        let simulated = simulate_quote(&mint, &TOKEN_PROGRAM_ID, 1_000, |ix| {
+            assert_eq!(ix, quote_ix(&mint, &TOKEN_PROGRAM_ID, 1_000));
+            Ok::<_, ()>(Some((PROGRAM_ID, quote.try_to_vec().unwrap())))
+        });
+        assert_eq!(simulated, Ok(Some(quote)));
+
+        // Return data from another program or no return data at all
+        let other = simulate_quote(&mint, &TOKEN_PROGRAM_ID, 1_000, |_| Ok::<_, ()>(Some((Pubkey::new_unique(), quote.try_to_vec().unwrap()))));
+        assert_eq!(other, Ok(None));
+        assert_eq!(simulate_quote(&mint, &TOKEN_PROGRAM_ID, 1_000, |_| Ok::<_, ()>(None)), Ok(None));
+
+        // Simulation errors are passed through
+        assert_eq!(simulate_quote(&mint, &TOKEN_PROGRAM_ID, 1_000, |_| Err("rpc down")), Err("rpc down"));
+
+        // The SOL pool is quoted with `quote_sol`, on its pool and lamport vault
+        let sol_ix = quote_sol_ix(2_000);
+        assert_eq!(&sol_ix.data[..8], instruction::QuoteSol::DISCRIMINATOR);
+        assert_eq!(u64::from_le_bytes(sol_ix.data[8..16].try_into().unwrap()), 2_000);
+        assert_eq!(account_key(&sol_ix, 0), Some(find_sol_pool_pda().0));
+        assert_eq!(account_key(&sol_ix, 1), Some(find_sol_vault_pda().0));
+        let simulated = simulate_sol_quote(2_000, |ix| {
+            assert_eq!(ix, quote_sol_ix(2_000));
+            Ok::<_, ()>(Some((PROGRAM_ID, quote.try_to_vec().unwrap())))
+        });
+        assert_eq!(simulated, Ok(Some(quote)));
+        assert_eq!(simulate_sol_quote(2_000, |_| Ok::<_, ()>(None)), Ok(None));
+
+        println!("✅ Simulated quotes test passed");
+    }
+}
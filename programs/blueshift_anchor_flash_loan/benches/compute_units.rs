@@ -0,0 +1,493 @@
+// Compute unit benchmark running the compiled program in LiteSVM
+//
+// Runs representative transactions against SPL Token, Token-2022 and SOL pools,
+// reads the units consumed by every instruction from the transaction logs and
+// fails when one of them goes past its limit in `benches/compute_units.txt`.
+//
+//   cargo bench --bench compute_units
+//   CU_BENCH_UPDATE=1 cargo bench --bench compute_units   # rewrite the limits
+//
+// Build the programs first (`anchor build` or `cargo build-sbf`), the bench skips
+// when `target/deploy/blueshift_anchor_flash_loan.so` or the `flash_loan_receiver`
+// fixture it calls back is missing. Keys are fixed so PDA bump searches, and
+// therefore the measurements, are reproducible.
+
+#![allow(deprecated)]
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use std::collections::BTreeMap;
+
+use common::{add_upgradeable_program, address, ata, instruction_compute_units, key, program_data_pda, sdk_instruction};
+use anchor_lang::prelude::{AccountMeta, Pubkey};
+use anchor_lang::solana_program::instruction::Instruction;
+use anchor_lang::solana_program::program_pack::Pack;
+use anchor_lang::solana_program::sysvar::instructions::ID as INSTRUCTIONS_SYSVAR_ID;
+use anchor_lang::{InstructionData, ToAccountMetas};
+use anchor_spl::associated_token::spl_associated_token_account::instruction::create_associated_token_account;
+use anchor_spl::token_2022::spl_token_2022;
+use blueshift_anchor_flash_loan::{
+    accounts, instruction, CONFIG_SEED, ID, LOAN_SEED, LP_MINT_SEED, POOL_SEED, SOL_POOL_MINT, SOL_VAULT_SEED,
+};
+use litesvm::LiteSVM;
+use solana_sdk::account::Account;
+use solana_sdk::instruction::Instruction as SdkInstruction;
+use solana_sdk::signature::{Keypair, Signer};
+use solana_sdk::transaction::Transaction;
+use spl_token::solana_program::program_option::COption;
+
+const PROGRAM_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../../target/deploy/blueshift_anchor_flash_loan.so");
+const RECEIVER_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../../target/deploy/flash_loan_receiver.so");
+const LIMITS_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/benches/compute_units.txt");
+/// Set to rewrite the limits from the current measurements
+const UPDATE_ENV: &str = "CU_BENCH_UPDATE";
+/// Headroom given to every measurement when the limits are rewritten, in percent
+const UPDATE_HEADROOM_PERCENT: u64 = 5;
+const FEE_BPS: u16 = 500;
+const DECIMALS: u8 = 6;
+const LIQUIDITY: u64 = 1_000_000_000;
+const BORROWER_FUNDS: u64 = 10_000_000;
+const LOAN_AMOUNT: u64 = 100_000_000;
+/// Share of the loan fees going to the protocol, so there are fees to collect
+const PROTOCOL_SHARE_BPS: u16 = 2_000;
+
+/// Units consumed by every benchmarked instruction, by name
+type Measurements = BTreeMap<String, u64>;
+
+struct Bench {
+    svm: LiteSVM,
+    admin: Keypair,
+    provider: Keypair,
+    borrower: Keypair,
+    units: Measurements,
+}
+
+fn config_pda() -> Pubkey {
+    Pubkey::find_program_address(&[CONFIG_SEED], &ID).0
+}
+
+fn pool_pda(mint: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[POOL_SEED, mint.as_ref()], &ID).0
+}
+
+fn lp_mint_pda(pool: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[LP_MINT_SEED, pool.as_ref()], &ID).0
+}
+
+/// Limits stored as `<instruction> <max units>` lines, `#` starting a comment
+fn parse_limits(text: &str) -> Measurements {
+    text.lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let (name, limit) = line.split_once(char::is_whitespace).unwrap_or_else(|| panic!("malformed limit line: {line}"));
+            let limit = limit.trim().parse().unwrap_or_else(|_| panic!("malformed limit for {name}: {limit}"));
+            (name.to_string(), limit)
+        })
+        .collect()
+}
+
+fn format_limits(units: &Measurements) -> String {
+    let mut text = String::from(
+        "# Compute unit limits of `cargo bench --bench compute_units`, one `<instruction> <max units>` per line\n\
+         # Regenerate with `CU_BENCH_UPDATE=1 cargo bench --bench compute_units`\n",
+    );
+    for (name, consumed) in units {
+        let limit = consumed + (consumed * UPDATE_HEADROOM_PERCENT).div_ceil(100);
+        text.push_str(&format!("{name} {limit}\n"));
+    }
+    text
+}
+
+impl Bench {
+    fn new(program: &[u8], receiver: &[u8]) -> Self {
+        let [admin, provider, borrower] = [1, 2, 3].map(|seed| Keypair::new_from_array([seed; 32]));
+
+        let mut svm = LiteSVM::new();
+        add_upgradeable_program(&mut svm, program, &key(&admin));
+        svm.add_program(address(&flash_loan_receiver::ID), receiver).unwrap();
+
+        for signer in [&admin, &provider, &borrower] {
+            svm.airdrop(&signer.pubkey(), 10_000_000_000).unwrap();
+        }
+
+        let mut bench = Self { svm, admin, provider, borrower, units: Measurements::new() };
+        let initialize_protocol = Instruction {
+            program_id: ID,
+            accounts: accounts::InitializeProtocol {
+                admin: key(&bench.admin),
+                config: config_pda(),
+                program: ID,
+                program_data: program_data_pda(),
+                system_program: anchor_lang::system_program::ID,
+            }
+            .to_account_metas(None),
+            data: instruction::InitializeProtocol { fee_bps: FEE_BPS }.data(),
+        };
+        bench.run(&["initialize_protocol"], vec![initialize_protocol], &bench.admin.insecure_clone());
+
+        let update_protocol_share = Instruction {
+            program_id: ID,
+            accounts: accounts::AdminOnly { admin: key(&bench.admin), config: config_pda() }.to_account_metas(None),
+            data: instruction::UpdateProtocolShare { protocol_share_bps: PROTOCOL_SHARE_BPS }.data(),
+        };
+        bench.run(&["update_protocol_share"], vec![update_protocol_share], &bench.admin.insecure_clone());
+
+        bench
+    }
+
+    /// Send `ixs` signed by `signer` and record the units of each instruction of this program under `names`
+    fn run(&mut self, names: &[&str], ixs: Vec<Instruction>, signer: &Keypair) {
+        let ixs: Vec<SdkInstruction> = ixs.into_iter().map(sdk_instruction).collect();
+        let tx = Transaction::new_signed_with_payer(&ixs, Some(&signer.pubkey()), &[signer], self.svm.latest_blockhash());
+        let logs = match self.svm.send_transaction(tx) {
+            Ok(meta) => meta.logs,
+            Err(failed) => panic!("{} failed with {:?}, logs: {:#?}", names.join(" + "), failed.err, failed.meta.logs),
+        };
+        self.record(names, &logs);
+    }
+
+    /// Same as `run` for read-only instructions, which are only simulated
+    fn simulate(&mut self, names: &[&str], ixs: Vec<Instruction>, signer: &Keypair) {
+        let ixs: Vec<SdkInstruction> = ixs.into_iter().map(sdk_instruction).collect();
+        let tx = Transaction::new_signed_with_payer(&ixs, Some(&signer.pubkey()), &[signer], self.svm.latest_blockhash());
+        let logs = match self.svm.simulate_transaction(tx) {
+            Ok(simulated) => simulated.meta.logs,
+            Err(failed) => panic!("{} failed with {:?}, logs: {:#?}", names.join(" + "), failed.err, failed.meta.logs),
+        };
+        self.record(names, &logs);
+    }
+
+    fn record(&mut self, names: &[&str], logs: &[String]) {
+        let units = instruction_compute_units(logs);
+        assert_eq!(units.len(), names.len(), "expected {} instructions, logs: {:#?}", names.len(), logs);
+        for (name, consumed) in names.iter().zip(units) {
+            self.units.insert(name.to_string(), consumed);
+        }
+    }
+
+    /// Pool of a fresh `token_program` mint: setup, deposit, quote, flash loans, fee collection and withdrawal
+    fn token_pool(&mut self, token_program: Pubkey, mint_seed: u8, suffix: &str) {
+        let admin = self.admin.insecure_clone();
+        let provider = self.provider.insecure_clone();
+        let borrower = self.borrower.insecure_clone();
+        let name = |instruction: &str| format!("{instruction}{suffix}");
+
+        // Mints without extensions are laid out the same by SPL Token and Token-2022
+        let mint = Pubkey::new_from_array([mint_seed; 32]);
+        let mut data = vec![0; spl_token::state::Mint::LEN];
+        spl_token::state::Mint {
+            mint_authority: COption::Some(key(&admin)),
+            decimals: DECIMALS,
+            is_initialized: true,
+            ..Default::default()
+        }
+        .pack_into_slice(&mut data);
+        let account = Account {
+            lamports: self.svm.minimum_balance_for_rent_exemption(data.len()),
+            data,
+            owner: address(&token_program),
+            executable: false,
+            rent_epoch: 0,
+        };
+        self.svm.set_account(address(&mint), account).unwrap();
+
+        let pool = pool_pda(&mint);
+        let vault = ata(&pool, &mint, &token_program);
+        let lp_mint = lp_mint_pda(&pool);
+        let initialize_pool = Instruction {
+            program_id: ID,
+            accounts: accounts::InitializePool {
+                admin: key(&admin),
+                config: config_pda(),
+                mint,
+                pool,
+                vault,
+                lp_mint,
+                token_program,
+                associated_token_program: anchor_spl::associated_token::ID,
+                system_program: anchor_lang::system_program::ID,
+            }
+            .to_account_metas(None),
+            data: instruction::InitializePool {}.data(),
+        };
+        self.run(&[&name("initialize_pool")], vec![initialize_pool], &admin);
+
+        let mut funding = Vec::new();
+        for (owner, amount) in [(&provider, LIQUIDITY), (&borrower, BORROWER_FUNDS)] {
+            funding.push(create_associated_token_account(&key(&admin), &key(owner), &mint, &token_program));
+            funding.push(
+                spl_token_2022::instruction::mint_to(&token_program, &mint, &ata(&key(owner), &mint, &token_program), &key(&admin), &[], amount)
+                    .unwrap(),
+            );
+        }
+        self.run(&[], funding, &admin);
+
+        let liquidity = |data: Vec<u8>| Instruction {
+            program_id: ID,
+            accounts: accounts::Liquidity {
+                provider: key(&provider),
+                pool,
+                mint,
+                lp_mint,
+                vault,
+                provider_ata: ata(&key(&provider), &mint, &token_program),
+                provider_lp_ata: ata(&key(&provider), &lp_mint, &token_program),
+                token_program,
+                associated_token_program: anchor_spl::associated_token::ID,
+                system_program: anchor_lang::system_program::ID,
+            }
+            .to_account_metas(None),
+            data,
+        };
+        self.run(&[&name("deposit")], vec![liquidity(instruction::Deposit { amount: LIQUIDITY }.data())], &provider);
+
+        let quote = Instruction {
+            program_id: ID,
+            accounts: accounts::Quote { pool, mint, vault, token_program }.to_account_metas(None),
+            data: instruction::Quote { amount: LOAN_AMOUNT }.data(),
+        };
+        self.simulate(&[&name("quote")], vec![quote], &borrower);
+
+        // The borrower ATA already exists, the loan receipt is created by the borrow
+        let borrower_ata = ata(&key(&borrower), &mint, &token_program);
+        let loan = Pubkey::find_program_address(&[LOAN_SEED, pool.as_ref(), key(&borrower).as_ref()], &ID).0;
+        let borrow = Instruction {
+            program_id: ID,
+            accounts: accounts::Borrow {
+                borrower: key(&borrower),
+                pool,
+                mint,
+                borrower_ata,
+                vault,
+                config: config_pda(),
+                loan,
+                instructions: INSTRUCTIONS_SYSVAR_ID,
+                token_program,
+                associated_token_program: anchor_spl::associated_token::ID,
+                system_program: anchor_lang::system_program::ID,
+            }
+            .to_account_metas(None),
+            data: instruction::Borrow { borrow_amount: LOAN_AMOUNT }.data(),
+        };
+        let repay = Instruction {
+            program_id: ID,
+            accounts: accounts::Repay {
+                borrower: key(&borrower),
+                pool,
+                mint,
+                borrower_ata,
+                vault,
+                config: config_pda(),
+                loan,
+                instructions: INSTRUCTIONS_SYSVAR_ID,
+                token_program,
+            }
+            .to_account_metas(None),
+            data: instruction::Repay {}.data(),
+        };
+        let check_repaid = Instruction {
+            program_id: ID,
+            accounts: [
+                accounts::CheckRepaid { instructions: INSTRUCTIONS_SYSVAR_ID }.to_account_metas(None),
+                vec![AccountMeta::new_readonly(loan, false)],
+            ]
+            .concat(),
+            data: instruction::CheckRepaid {}.data(),
+        };
+        self.run(&[&name("borrow"), &name("repay"), &name("check_repaid")], vec![borrow, repay, check_repaid], &borrower);
+
+        // The receiver fixture holds the fee and gets the principal from the loan
+        let receiver_authority = Pubkey::find_program_address(&[flash_loan_receiver::AUTHORITY_SEED], &flash_loan_receiver::ID).0;
+        let receiver_ata = ata(&receiver_authority, &mint, &token_program);
+        let fee = LOAN_AMOUNT * FEE_BPS as u64 / 10_000;
+        let funding = vec![
+            create_associated_token_account(&key(&admin), &receiver_authority, &mint, &token_program),
+            spl_token_2022::instruction::mint_to(&token_program, &mint, &receiver_ata, &key(&admin), &[], fee).unwrap(),
+        ];
+        self.run(&[], funding, &admin);
+
+        let mut flash_loan_accounts = accounts::FlashLoan {
+            borrower: key(&borrower),
+            pool,
+            mint,
+            vault,
+            receiver_token_account: receiver_ata,
+            config: config_pda(),
+            receiver_program: flash_loan_receiver::ID,
+            token_program,
+        }
+        .to_account_metas(None);
+        flash_loan_accounts.push(AccountMeta::new_readonly(receiver_authority, false));
+        let flash_loan = Instruction {
+            program_id: ID,
+            accounts: flash_loan_accounts,
+            data: instruction::FlashLoan { amount: LOAN_AMOUNT, data: vec![] }.data(),
+        };
+        self.run(&[&name("flash_loan")], vec![flash_loan], &borrower);
+
+        // The treasury is the admin by default, its ATA is created by the first collection
+        let collect_protocol_fees = Instruction {
+            program_id: ID,
+            accounts: accounts::CollectProtocolFees {
+                admin: key(&admin),
+                config: config_pda(),
+                pool,
+                mint,
+                vault,
+                treasury: key(&admin),
+                treasury_ata: ata(&key(&admin), &mint, &token_program),
+                token_program,
+                associated_token_program: anchor_spl::associated_token::ID,
+                system_program: anchor_lang::system_program::ID,
+            }
+            .to_account_metas(None),
+            data: instruction::CollectProtocolFees {}.data(),
+        };
+        self.run(&[&name("collect_protocol_fees")], vec![collect_protocol_fees], &admin);
+
+        self.run(&[&name("withdraw")], vec![liquidity(instruction::Withdraw { shares: LIQUIDITY / 2 }.data())], &provider);
+    }
+
+    /// SOL pool: setup, deposit, quote, flash loan, fee collection and withdrawal
+    fn sol_pool(&mut self) {
+        let admin = self.admin.insecure_clone();
+        let provider = self.provider.insecure_clone();
+        let borrower = self.borrower.insecure_clone();
+
+        let pool = pool_pda(&SOL_POOL_MINT);
+        let vault = Pubkey::find_program_address(&[SOL_VAULT_SEED, pool.as_ref()], &ID).0;
+        let lp_mint = lp_mint_pda(&pool);
+
+        let initialize_sol_pool = Instruction {
+            program_id: ID,
+            accounts: accounts::InitializeSolPool {
+                admin: key(&admin),
+                config: config_pda(),
+                pool,
+                vault,
+                lp_mint,
+                token_program: anchor_spl::token::ID,
+                system_program: anchor_lang::system_program::ID,
+            }
+            .to_account_metas(None),
+            data: instruction::InitializeSolPool {}.data(),
+        };
+        self.run(&["initialize_sol_pool"], vec![initialize_sol_pool], &admin);
+
+        let sol_liquidity = |data: Vec<u8>| Instruction {
+            program_id: ID,
+            accounts: accounts::SolLiquidity {
+                provider: key(&provider),
+                pool,
+                vault,
+                lp_mint,
+                provider_lp_ata: ata(&key(&provider), &lp_mint, &anchor_spl::token::ID),
+                token_program: anchor_spl::token::ID,
+                associated_token_program: anchor_spl::associated_token::ID,
+                system_program: anchor_lang::system_program::ID,
+            }
+            .to_account_metas(None),
+            data,
+        };
+        self.run(&["deposit_sol"], vec![sol_liquidity(instruction::DepositSol { amount: LIQUIDITY }.data())], &provider);
+
+        let quote_sol = Instruction {
+            program_id: ID,
+            accounts: accounts::QuoteSol { pool, vault }.to_account_metas(None),
+            data: instruction::QuoteSol { amount: LOAN_AMOUNT }.data(),
+        };
+        self.simulate(&["quote_sol"], vec![quote_sol], &borrower);
+
+        let loan = Pubkey::find_program_address(&[LOAN_SEED, pool.as_ref(), key(&borrower).as_ref()], &ID).0;
+        let borrow_sol = Instruction {
+            program_id: ID,
+            accounts: accounts::BorrowSol {
+                borrower: key(&borrower),
+                pool,
+                config: config_pda(),
+                loan,
+                vault,
+                instructions: INSTRUCTIONS_SYSVAR_ID,
+                system_program: anchor_lang::system_program::ID,
+            }
+            .to_account_metas(None),
+            data: instruction::BorrowSol { borrow_amount: LOAN_AMOUNT }.data(),
+        };
+        let repay_sol = Instruction {
+            program_id: ID,
+            accounts: accounts::RepaySol {
+                borrower: key(&borrower),
+                pool,
+                config: config_pda(),
+                loan,
+                vault,
+                instructions: INSTRUCTIONS_SYSVAR_ID,
+                system_program: anchor_lang::system_program::ID,
+            }
+            .to_account_metas(None),
+            data: instruction::RepaySol {}.data(),
+        };
+        let ixs = vec![borrow_sol, repay_sol];
+        self.run(&["borrow_sol", "repay_sol"], ixs, &borrower);
+
+        let collect_sol_protocol_fees = Instruction {
+            program_id: ID,
+            accounts: accounts::CollectSolProtocolFees { admin: key(&admin), config: config_pda(), pool, vault, treasury: key(&admin) }
+                .to_account_metas(None),
+            data: instruction::CollectSolProtocolFees {}.data(),
+        };
+        self.run(&["collect_sol_protocol_fees"], vec![collect_sol_protocol_fees], &admin);
+
+        self.run(&["withdraw_sol"], vec![sol_liquidity(instruction::WithdrawSol { shares: LIQUIDITY / 2 }.data())], &provider);
+    }
+}
+
+fn main() {
+    let Ok(program) = std::fs::read(PROGRAM_PATH) else {
+        println!("⚠️  Skipping: {} not found, build the program first", PROGRAM_PATH);
+        return;
+    };
+    let Ok(receiver) = std::fs::read(RECEIVER_PATH) else {
+        println!("⚠️  Skipping: {} not found, build the programs first", RECEIVER_PATH);
+        return;
+    };
+
+    println!("🚀 Benchmarking compute units");
+    let mut bench = Bench::new(&program, &receiver);
+    bench.token_pool(anchor_spl::token::ID, 10, "");
+    bench.token_pool(spl_token_2022::ID, 11, "_token_2022");
+    bench.sol_pool();
+    let units = bench.units;
+
+    if std::env::var_os(UPDATE_ENV).is_some() {
+        std::fs::write(LIMITS_PATH, format_limits(&units)).expect("limits should be writable");
+        println!("✅ Limits of {} instructions written to {}", units.len(), LIMITS_PATH);
+        return;
+    }
+
+    let limits = parse_limits(&std::fs::read_to_string(LIMITS_PATH).expect("limits should be readable"));
+    let mut regressions = Vec::new();
+    println!("   {:<28} {:>10} {:>10}", "instruction", "units", "limit");
+    for (name, consumed) in &units {
+        let limit = limits.get(name).copied();
+        let status = match limit {
+            Some(limit) if *consumed <= limit => "✅",
+            _ => {
+                regressions.push(name.as_str());
+                "❌"
+            }
+        };
+        let limit = limit.map_or_else(|| "none".to_string(), |limit| limit.to_string());
+        println!("   {status} {:<26} {:>10} {:>10}", name, consumed, limit);
+    }
+
+    if !regressions.is_empty() {
+        eprintln!("❌ Compute units over their limit (or without one): {}", regressions.join(", "));
+        eprintln!("   Fix the regression, or run with {UPDATE_ENV}=1 if the increase is intended");
+        std::process::exit(1);
+    }
+
+    println!("✅ Every instruction is within its compute unit limit");
+}
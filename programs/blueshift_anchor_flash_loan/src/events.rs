@@ -0,0 +1,99 @@
+use anchor_lang::prelude::*;
+use anchor_lang::Discriminator;
+
+/// Emitted by `borrow` once the funds left the vault
+#[event]
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoanOpened {
+  pub borrower: Pubkey,
+  pub mint: Pubkey,
+  pub amount: u64,
+  pub fee_bps: u16,
+  pub slot: u64,
+}
+
+/// Emitted by `repay` once the loan is settled
+#[event]
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoanRepaid {
+  pub borrower: Pubkey,
+  pub mint: Pubkey,
+  pub principal: u64,
+  pub fee: u64,
+  pub slot: u64,
+}
+
+/// Any event emitted by this program
+#[derive(Clone, Debug, PartialEq)]
+pub enum FlashLoanEvent {
+  LoanOpened(LoanOpened),
+  LoanRepaid(LoanRepaid),
+}
+
+impl FlashLoanEvent {
+  /// Decode an event from the bytes logged by `emit!` (discriminator followed by the event)
+  pub fn decode(data: &[u8]) -> Option<Self> {
+    let discriminator = data.get(..8)?;
+    let body = &data[8..];
+
+    if discriminator == LoanOpened::DISCRIMINATOR {
+      LoanOpened::try_from_slice(body).ok().map(Self::LoanOpened)
+    } else if discriminator == LoanRepaid::DISCRIMINATOR {
+      LoanRepaid::try_from_slice(body).ok().map(Self::LoanRepaid)
+    } else {
+      None
+    }
+  }
+
+  /// Decode an event from a `Program data: <base64>` log line
+  #[cfg(not(target_os = "solana"))]
+  pub fn from_log(log: &str) -> Option<Self> {
+    use base64::Engine;
+
+    let encoded = log.strip_prefix(PROGRAM_DATA_PREFIX)?;
+    let data = base64::engine::general_purpose::STANDARD.decode(encoded.trim()).ok()?;
+    Self::decode(&data)
+  }
+}
+
+#[cfg(not(target_os = "solana"))]
+const PROGRAM_DATA_PREFIX: &str = "Program data: ";
+
+/// Decode every event emitted by this program in the logs of a transaction
+///
+/// Events logged by other programs are skipped by following the invoke/return lines.
+#[cfg(not(target_os = "solana"))]
+pub fn parse_logs<S: AsRef<str>>(logs: &[S]) -> Vec<FlashLoanEvent> {
+  let program_id = crate::ID.to_string();
+  let mut stack: Vec<&str> = Vec::new();
+  let mut events = Vec::new();
+
+  for log in logs {
+    let log = log.as_ref();
+
+    if log.starts_with(PROGRAM_DATA_PREFIX) {
+      if stack.last() == Some(&program_id.as_str()) {
+        events.extend(FlashLoanEvent::from_log(log));
+      }
+    } else if let Some(rest) = log.strip_prefix("Program ") {
+      let mut words = rest.split_whitespace();
+      let (Some(program), Some(action)) = (words.next(), words.next()) else {
+        continue;
+      };
+      // Skip `Program log:` and friends
+      if program.parse::<Pubkey>().is_err() {
+        continue;
+      }
+
+      match action {
+        "invoke" => stack.push(program),
+        "success" | "failed:" => {
+          stack.pop();
+        }
+        _ => {}
+      }
+    }
+  }
+
+  events
+}
@@ -0,0 +1,80 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token_2022::spl_token_2022::{
+  self,
+  extension::{
+    transfer_fee::{TransferFee, TransferFeeConfig},
+    BaseStateWithExtensions,
+    ExtensionType,
+    StateWithExtensions,
+  },
+};
+
+use crate::ProtocolError;
+
+/// Token-2022 mint extensions a pool can be opened for
+///
+/// TransferFeeConfig is handled by the pool `TransferFeeMode`, the others leave
+/// the vault accounting untouched. Everything else is refused, in particular:
+///   TransferHook: runs foreign code on every transfer and needs extra accounts
+///   PermanentDelegate: lets a third party move the vault funds
+///   DefaultAccountState: new accounts (vault, borrower ATA) can start frozen
+///   NonTransferable, confidential transfers and any extension added later
+pub const SUPPORTED_MINT_EXTENSIONS: [ExtensionType; 9] = [
+  ExtensionType::TransferFeeConfig,
+  ExtensionType::MintCloseAuthority,
+  ExtensionType::InterestBearingConfig,
+  ExtensionType::MetadataPointer,
+  ExtensionType::TokenMetadata,
+  ExtensionType::GroupPointer,
+  ExtensionType::TokenGroup,
+  ExtensionType::GroupMemberPointer,
+  ExtensionType::TokenGroupMember,
+];
+
+/// Make sure a mint owned by `owner` with account `data` can be lent by a pool
+///
+/// Legacy SPL Token mints have no extensions and are always accepted.
+pub fn check_mint_extensions(owner: &Pubkey, data: &[u8]) -> Result<()> {
+  if *owner != spl_token_2022::ID {
+    return Ok(());
+  }
+
+  let mint = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(data)?;
+  for extension in mint.get_extension_types()? {
+    require!(SUPPORTED_MINT_EXTENSIONS.contains(&extension), ProtocolError::UnsupportedMintExtension);
+  }
+
+  Ok(())
+}
+
+/// Transfer fee charged by a mint owned by `owner` with account `data` during `epoch`, if any
+pub fn mint_transfer_fee(owner: &Pubkey, data: &[u8], epoch: u64) -> Result<Option<TransferFee>> {
+  if *owner != spl_token_2022::ID {
+    return Ok(None);
+  }
+
+  let mint = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(data)?;
+  let Ok(config) = mint.get_extension::<TransferFeeConfig>() else {
+    return Ok(None);
+  };
+  let transfer_fee = *config.get_epoch_fee(epoch);
+
+  // A fee schedule of 0 bps is the same as no fee
+  Ok((u16::from(transfer_fee.transfer_fee_basis_points) > 0).then_some(transfer_fee))
+}
+
+/// Amount credited to the recipient of a transfer of `amount`
+pub fn amount_received(transfer_fee: Option<&TransferFee>, amount: u64) -> Result<u64> {
+  match transfer_fee {
+    Some(transfer_fee) => transfer_fee.calculate_post_fee_amount(amount).ok_or(ProtocolError::Overflow.into()),
+    None => Ok(amount),
+  }
+}
+
+/// Amount to transfer so that the recipient is credited at least `net`
+pub fn amount_to_send(transfer_fee: Option<&TransferFee>, net: u64) -> Result<u64> {
+  match transfer_fee {
+    Some(transfer_fee) => transfer_fee.calculate_pre_fee_amount(net).ok_or(ProtocolError::Overflow.into()),
+    None => Ok(net),
+  }
+}
@@ -0,0 +1,49 @@
+use anchor_lang::prelude::*;
+
+use crate::{ProtocolError, BPS_DENOMINATOR};
+
+/// How fees falling between two token units are rounded
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq, InitSpace)]
+pub enum Rounding {
+  /// Round towards 0, in favour of the borrower
+  Down,
+  /// Round away from 0, in favour of the pool
+  Up,
+}
+
+/// Fee charged on a loan of `amount` at `fee_bps`
+///
+/// Rates up to `MAX_FEE_BPS` keep the fee at most `amount`, higher ones fail
+/// with `Overflow` once the fee no longer fits in a u64.
+pub fn compute_fee(amount: u64, fee_bps: u16, rounding: Rounding) -> Result<u64> {
+  let numerator = amount as u128 * fee_bps as u128;
+  let fee = match rounding {
+    Rounding::Down => numerator / BPS_DENOMINATOR,
+    Rounding::Up => numerator.div_ceil(BPS_DENOMINATOR),
+  };
+
+  u64::try_from(fee).map_err(|_| ProtocolError::Overflow.into())
+}
+
+/// Fee charged on a loan of `amount` at `fee_bps`, never less than `min_fee`
+///
+/// The minimum fee keeps a loan split into many tiny ones from paying nothing.
+pub fn loan_fee(amount: u64, fee_bps: u16, rounding: Rounding, min_fee: u64) -> Result<u64> {
+  Ok(compute_fee(amount, fee_bps, rounding)?.max(min_fee))
+}
+
+/// Principal plus fee owed for a loan of `amount` at `fee_bps`, the fee being at least `min_fee`
+pub fn total_due(amount: u64, fee_bps: u16, rounding: Rounding, min_fee: u64) -> Result<u64> {
+  amount
+    .checked_add(loan_fee(amount, fee_bps, rounding, min_fee)?)
+    .ok_or(ProtocolError::Overflow.into())
+}
+
+/// Part of `fee` owed to the protocol at `protocol_share_bps`, the rest going to the liquidity providers
+///
+/// Rounds down, so the liquidity providers keep the dust.
+pub fn protocol_fee(fee: u64, protocol_share_bps: u16) -> u64 {
+  // Capped at 100%, so the share never exceeds the fee and fits in a u64
+  let share = (protocol_share_bps as u128).min(BPS_DENOMINATOR);
+  (fee as u128 * share / BPS_DENOMINATOR) as u64
+}
@@ -0,0 +1,270 @@
+use anchor_lang::prelude::*;
+use anchor_lang::{
+  Discriminator,
+  solana_program::{
+    instruction::{Instruction, TRANSACTION_LEVEL_STACK_HEIGHT},
+    pubkey,
+  },
+};
+
+use crate::{instruction, ProtocolError, ID};
+
+/// Position of the borrower ATA in the `Borrow` and `Repay` accounts
+pub const BORROWER_ATA_INDEX: usize = 3;
+/// Position of the loan receipt PDA in the `BorrowSol` and `RepaySol` accounts
+pub const SOL_LOAN_INDEX: usize = 3;
+/// Position of the pool vault in the `Borrow`, `Repay`, `BorrowSol` and `RepaySol` accounts, borrows and repays are paired on it
+pub const VAULT_INDEX: usize = 4;
+
+/// Compute budget program, used to set compute limits and priority fees
+pub const COMPUTE_BUDGET_PROGRAM_ID: Pubkey = pubkey!("ComputeBudget111111111111111111111111111111");
+/// SPL Memo program
+pub const MEMO_PROGRAM_ID: Pubkey = pubkey!("MemoSq4gqABAXKb96qnH8TysNcWxMyWCqXgDLGmfcHr");
+
+const PUBKEY_LEN: usize = 32;
+const IS_SIGNER_BIT: u8 = 1 << 0;
+const IS_WRITABLE_BIT: u8 = 1 << 1;
+
+/// Read-only view over the instructions sysvar data
+///
+/// Layout:
+///   u16 number of instructions
+///   u16 offset of every instruction
+///   per instruction: u16 number of accounts, (u8 flags + pubkey) per account, program id, u16 data len, data
+///   u16 index of the currently executing instruction
+pub struct InstructionsSysvar<'a> {
+  /// Instructions, without the trailing current index
+  data: &'a [u8],
+  len: usize,
+  current_index: usize,
+}
+
+impl<'a> InstructionsSysvar<'a> {
+  /// Validate the header and trailing current index of the sysvar data
+  pub fn new(data: &'a [u8]) -> Result<Self> {
+    let len = read_u16(data, 0)? as usize;
+
+    // Offsets table followed by at least the current index
+    let header_end = len
+        .checked_mul(2)
+        .and_then(|offsets| offsets.checked_add(2))
+        .ok_or(ProtocolError::MalformedInstructionsSysvar)?;
+    require!(header_end.checked_add(2).is_some_and(|end| end <= data.len()), ProtocolError::MalformedInstructionsSysvar);
+
+    let (data, current_index) = data.split_at(data.len() - 2);
+    let current_index = u16::from_le_bytes([current_index[0], current_index[1]]) as usize;
+    require!(current_index < len, ProtocolError::MalformedInstructionsSysvar);
+
+    Ok(Self { data, len, current_index })
+  }
+
+  /// Number of top-level instructions in the transaction
+  pub fn len(&self) -> usize {
+    self.len
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
+  /// Index of the instruction currently being executed
+  pub fn current_index(&self) -> usize {
+    self.current_index
+  }
+
+  /// Decode the instruction at `index`
+  pub fn load_instruction(&self, index: usize) -> Result<Instruction> {
+    require!(index < self.len, ProtocolError::InvalidInstructionIndex);
+
+    let mut cursor = read_u16(self.data, 2 + index * 2)? as usize;
+
+    let num_accounts = read_u16(self.data, cursor)? as usize;
+    cursor += 2;
+
+    let mut accounts = Vec::with_capacity(num_accounts);
+    for _ in 0..num_accounts {
+      let flags = read_u8(self.data, cursor)?;
+      let pubkey = read_pubkey(self.data, cursor + 1)?;
+      cursor += 1 + PUBKEY_LEN;
+
+      accounts.push(AccountMeta {
+          pubkey,
+          is_signer: flags & IS_SIGNER_BIT != 0,
+          is_writable: flags & IS_WRITABLE_BIT != 0,
+      });
+    }
+
+    let program_id = read_pubkey(self.data, cursor)?;
+    cursor += PUBKEY_LEN;
+
+    let data_len = read_u16(self.data, cursor)? as usize;
+    cursor += 2;
+    let data = read_slice(self.data, cursor, data_len)?.to_vec();
+
+    Ok(Instruction { program_id, accounts, data })
+  }
+}
+
+/// Whether `ix` is a `borrow` or `borrow_sol` of this program
+pub fn is_borrow(ix: &Instruction) -> bool {
+  ix.program_id == ID
+      && ix.data.get(0..8).is_some_and(|d| {
+          d.eq(instruction::Borrow::DISCRIMINATOR) || d.eq(instruction::BorrowSol::DISCRIMINATOR)
+      })
+}
+
+/// Whether `ix` is a `repay` or `repay_sol` of this program
+pub fn is_repay(ix: &Instruction) -> bool {
+  ix.program_id == ID
+      && ix.data.get(0..8).is_some_and(|d| {
+          d.eq(instruction::Repay::DISCRIMINATOR) || d.eq(instruction::RepaySol::DISCRIMINATOR)
+      })
+}
+
+/// Whether `ix` is a `check_repaid` of this program
+pub fn is_repay_check(ix: &Instruction) -> bool {
+  ix.program_id == ID && ix.data.get(0..8).is_some_and(|d| d.eq(instruction::CheckRepaid::DISCRIMINATOR))
+}
+
+/// Find the first `check_repaid` listing `receipt` after the top-level instruction at `index`
+///
+/// Inner instructions are not visible in the sysvar, so a loan opened through a
+/// CPI can only be proven settled by a later top-level instruction that fails
+/// while its receipt is still open.
+pub fn find_repay_check(sysvar: &InstructionsSysvar, index: usize, receipt: &Pubkey) -> Result<usize> {
+  for i in index + 1..sysvar.len() {
+    let ix = sysvar.load_instruction(i)?;
+    if is_repay_check(&ix) && ix.accounts.iter().any(|meta| meta.pubkey == *receipt) {
+      return Ok(i);
+    }
+  }
+
+  err!(ProtocolError::MissingRepayCheck)
+}
+
+/// How the executing instruction was invoked
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Invocation {
+  /// Directly by the transaction, at the sysvar current index
+  TopLevel,
+  /// Through a CPI by `caller`, from the top-level instruction at `index`
+  Cpi { caller: Pubkey, index: usize },
+}
+
+/// Find how the executing instruction was invoked, `stack_height` being the current stack height
+///
+/// Under a CPI the sysvar current index points at the top-level instruction of
+/// the caller, not at a loan instruction. Only direct CPIs are supported: deeper
+/// in the stack the top-level program is not the one calling this program.
+pub fn invocation(sysvar: &InstructionsSysvar, stack_height: usize) -> Result<Invocation> {
+  if stack_height == TRANSACTION_LEVEL_STACK_HEIGHT {
+    return Ok(Invocation::TopLevel);
+  }
+  require!(stack_height == TRANSACTION_LEVEL_STACK_HEIGHT + 1, ProtocolError::CpiTooDeep);
+
+  let index = sysvar.current_index();
+  let caller = sysvar.load_instruction(index)?.program_id;
+
+  Ok(Invocation::Cpi { caller, index })
+}
+
+/// A `borrow` and the `repay` settling it
+pub struct LoanPair {
+  pub borrow_index: usize,
+  pub borrow: Instruction,
+  pub repay_index: usize,
+  pub repay: Instruction,
+}
+
+/// Pair every `borrow` of the transaction with the `repay` settling it
+///
+/// A repay settles the latest open borrow on the same vault, so several loans
+/// (on the same or different pools) can run in one transaction and loans on the
+/// same vault unwind innermost first. Fails if a repay has nothing to settle or
+/// if a borrow is never repaid.
+pub fn pair_loans(sysvar: &InstructionsSysvar) -> Result<Vec<LoanPair>> {
+  let mut open: Vec<(usize, Instruction)> = Vec::new();
+  let mut pairs = Vec::new();
+
+  for i in 0..sysvar.len() {
+    let ix = sysvar.load_instruction(i)?;
+
+    if is_borrow(&ix) {
+      open.push((i, ix));
+    } else if is_repay(&ix) {
+      let vault = account_key(&ix, VAULT_INDEX).ok_or(ProtocolError::InvalidVault)?;
+      let position = open
+          .iter()
+          .rposition(|(_, borrow)| account_key(borrow, VAULT_INDEX) == Some(vault))
+          .ok_or(ProtocolError::MissingBorrowIx)?;
+      let (borrow_index, borrow) = open.remove(position);
+
+      pairs.push(LoanPair { borrow_index, borrow, repay_index: i, repay: ix });
+    }
+  }
+
+  require!(open.is_empty(), ProtocolError::MissingRepayIx);
+
+  Ok(pairs)
+}
+
+/// Run the transaction checks of the borrow currently executing and return its loan
+///
+/// Every borrow of the transaction must be repaid, and only `allowed` programs
+/// may run before the first one.
+pub fn validate_borrow(sysvar: &InstructionsSysvar, allowed: &[Pubkey]) -> Result<LoanPair> {
+  let pairs = pair_loans(sysvar)?;
+
+  let first_borrow_index = pairs.iter().map(|pair| pair.borrow_index).min().unwrap_or(0);
+  check_leading_instructions(sysvar, first_borrow_index, allowed)?;
+
+  let current_index = sysvar.current_index();
+  pairs
+      .into_iter()
+      .find(|pair| pair.borrow_index == current_index)
+      .ok_or(ProtocolError::MissingRepayIx.into())
+}
+
+/// Find the `borrow` paired with the `repay` at `repay_index`
+pub fn find_paired_borrow(sysvar: &InstructionsSysvar, repay_index: usize) -> Result<LoanPair> {
+  pair_loans(sysvar)?
+      .into_iter()
+      .find(|pair| pair.repay_index == repay_index)
+      .ok_or(ProtocolError::MissingBorrowIx.into())
+}
+
+/// Make sure every instruction before `index` belongs to one of the `allowed` programs
+pub fn check_leading_instructions(sysvar: &InstructionsSysvar, index: usize, allowed: &[Pubkey]) -> Result<()> {
+  for i in 0..index {
+    let ix = sysvar.load_instruction(i)?;
+    require!(allowed.contains(&ix.program_id), ProtocolError::InvalidIx);
+  }
+
+  Ok(())
+}
+
+/// Key of the account at `index` in `ix`
+pub fn account_key(ix: &Instruction, index: usize) -> Option<Pubkey> {
+  ix.accounts.get(index).map(|meta| meta.pubkey)
+}
+
+fn read_slice(data: &[u8], start: usize, len: usize) -> Result<&[u8]> {
+  start
+      .checked_add(len)
+      .and_then(|end| data.get(start..end))
+      .ok_or(ProtocolError::MalformedInstructionsSysvar.into())
+}
+
+fn read_u8(data: &[u8], start: usize) -> Result<u8> {
+  Ok(read_slice(data, start, 1)?[0])
+}
+
+fn read_u16(data: &[u8], start: usize) -> Result<u16> {
+  let bytes = read_slice(data, start, 2)?;
+  Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_pubkey(data: &[u8], start: usize) -> Result<Pubkey> {
+  let bytes = read_slice(data, start, PUBKEY_LEN)?;
+  Ok(Pubkey::try_from(bytes).map_err(|_| ProtocolError::MalformedInstructionsSysvar)?)
+}
@@ -3,49 +3,419 @@
 #![allow(ambiguous_glob_reexports)]
 use anchor_lang::prelude::*;
 use anchor_spl::{
-  token::{Token, TokenAccount, Mint, Transfer, transfer}, 
-  associated_token::AssociatedToken
+  token_interface::{TokenInterface, TokenAccount, Mint, TransferChecked, transfer_checked, MintTo, mint_to, Burn, burn}, 
+  associated_token::AssociatedToken,
+  token_2022::spl_token_2022::extension::transfer_fee::TransferFee,
 }; 
-use anchor_lang::{
-  Discriminator,
-  solana_program::sysvar::instructions::{
-      ID as INSTRUCTIONS_SYSVAR_ID,
-      load_instruction_at_checked,
-      load_current_index_checked
-  }
+use anchor_lang::solana_program::{
+  instruction::{AccountMeta, get_stack_height},
+  program::invoke,
+  sysvar::instructions::ID as INSTRUCTIONS_SYSVAR_ID,
+};
+use anchor_lang::system_program::{Transfer as SystemTransfer, transfer as system_transfer};
+
+pub mod events;
+pub mod extensions;
+pub mod fees;
+pub mod introspection;
+pub mod receiver;
+pub mod state;
+pub use events::*;
+pub use state::*;
+
+use extensions::{amount_received, amount_to_send, check_mint_extensions, mint_transfer_fee};
+use fees::Rounding;
+use introspection::{
+  InstructionsSysvar,
+  Invocation,
+  BORROWER_ATA_INDEX,
+  COMPUTE_BUDGET_PROGRAM_ID,
+  MEMO_PROGRAM_ID,
+  SOL_LOAN_INDEX,
+  VAULT_INDEX,
+  account_key,
+  find_paired_borrow,
+  find_repay_check,
+  invocation,
+  validate_borrow
 };
+use receiver::OnFlashLoan;
  
 declare_id!("22222222222222222222222222222222222222222222");
+
+/// Basis points denominator used for fee calculations
+pub const BPS_DENOMINATOR: u128 = 10_000;
+/// Highest fee the admin is allowed to configure (100%)
+pub const MAX_FEE_BPS: u16 = 10_000;
  
 #[program]
 pub mod blueshift_anchor_flash_loan {
   use super::*;
+
+  pub fn initialize_protocol(ctx: Context<InitializeProtocol>, fee_bps: u16) -> Result<()> {
+    require!(fee_bps <= MAX_FEE_BPS, ProtocolError::InvalidFee);
+
+    ctx.accounts.config.set_inner(ProtocolConfig {
+        admin: ctx.accounts.admin.key(),
+        guardian: ctx.accounts.admin.key(),
+        paused: false,
+        fee_bps,
+        protocol_share_bps: 0,
+        treasury: ctx.accounts.admin.key(),
+        allowed_leading_programs: vec![COMPUTE_BUDGET_PROGRAM_ID, MEMO_PROGRAM_ID],
+        allowed_cpi_callers: Vec::new(),
+        bump: ctx.bumps.config,
+    });
+
+    Ok(())
+  }
+
+  pub fn update_fee(ctx: Context<AdminOnly>, fee_bps: u16) -> Result<()> {
+    require!(fee_bps <= MAX_FEE_BPS, ProtocolError::InvalidFee);
+
+    ctx.accounts.config.fee_bps = fee_bps;
+
+    Ok(())
+  }
+
+  pub fn update_protocol_share(ctx: Context<AdminOnly>, protocol_share_bps: u16) -> Result<()> {
+    require!(protocol_share_bps as u128 <= BPS_DENOMINATOR, ProtocolError::InvalidProtocolShare);
+
+    ctx.accounts.config.protocol_share_bps = protocol_share_bps;
+
+    Ok(())
+  }
+
+  pub fn set_treasury(ctx: Context<AdminOnly>, treasury: Pubkey) -> Result<()> {
+    ctx.accounts.config.treasury = treasury;
+
+    Ok(())
+  }
+
+  pub fn set_admin(ctx: Context<AdminOnly>, new_admin: Pubkey) -> Result<()> {
+    ctx.accounts.config.admin = new_admin;
+
+    Ok(())
+  }
+
+  pub fn set_guardian(ctx: Context<AdminOnly>, guardian: Pubkey) -> Result<()> {
+    ctx.accounts.config.guardian = guardian;
+
+    Ok(())
+  }
+
+  pub fn set_paused(ctx: Context<SetPaused>, paused: bool) -> Result<()> {
+    ctx.accounts.config.paused = paused;
+
+    Ok(())
+  }
+
+  pub fn set_leading_programs(ctx: Context<AdminOnly>, programs: Vec<Pubkey>) -> Result<()> {
+    require!(programs.len() <= MAX_LEADING_PROGRAMS, ProtocolError::TooManyLeadingPrograms);
+    // Letting this program lead would allow instructions to run against a borrow placed later on
+    require!(!programs.contains(&ID), ProtocolError::InvalidProgram);
+
+    ctx.accounts.config.allowed_leading_programs = programs;
+
+    Ok(())
+  }
+
+  pub fn set_cpi_callers(ctx: Context<AdminOnly>, programs: Vec<Pubkey>) -> Result<()> {
+    require!(programs.len() <= MAX_CPI_CALLERS, ProtocolError::TooManyCpiCallers);
+    require!(!programs.contains(&ID), ProtocolError::InvalidProgram);
+
+    ctx.accounts.config.allowed_cpi_callers = programs;
+
+    Ok(())
+  }
+
+  pub fn initialize_pool(ctx: Context<InitializePool>) -> Result<()> {
+    // Only lend mints whose extensions keep the vault accounting exact
+    let mint_info = ctx.accounts.mint.to_account_info();
+    check_mint_extensions(mint_info.owner, &mint_info.try_borrow_data()?)?;
+
+    ctx.accounts.pool.set_inner(Pool {
+        mint: ctx.accounts.mint.key(),
+        vault: ctx.accounts.vault.key(),
+        lp_mint: ctx.accounts.lp_mint.key(),
+        fee_bps: ctx.accounts.config.fee_bps,
+        fee_rounding: Rounding::Up,
+        min_fee: 0,
+        max_borrow: 0,
+        max_utilization_bps: 0,
+        transfer_fee_mode: TransferFeeMode::Refuse,
+        outstanding: 0,
+        total_borrowed: 0,
+        lp_fees: 0,
+        protocol_fees: 0,
+        unclaimed_protocol_fees: 0,
+        loan_count: 0,
+        bump: ctx.bumps.pool,
+    });
+
+    Ok(())
+  }
+
+  pub fn update_pool(
+    ctx: Context<UpdatePool>,
+    fee_bps: u16,
+    fee_rounding: Rounding,
+    min_fee: u64,
+    max_borrow: u64,
+    max_utilization_bps: u16,
+    transfer_fee_mode: TransferFeeMode,
+  ) -> Result<()> {
+    require!(fee_bps <= MAX_FEE_BPS, ProtocolError::InvalidFee);
+    require!(max_utilization_bps as u128 <= BPS_DENOMINATOR, ProtocolError::InvalidUtilization);
+
+    let pool = &mut ctx.accounts.pool;
+    pool.fee_bps = fee_bps;
+    pool.fee_rounding = fee_rounding;
+    pool.min_fee = min_fee;
+    pool.max_borrow = max_borrow;
+    pool.max_utilization_bps = max_utilization_bps;
+    pool.transfer_fee_mode = transfer_fee_mode;
+
+    Ok(())
+  }
+
+  pub fn quote(ctx: Context<Quote>, amount: u64) -> Result<LoanQuote> {
+    require!(amount > 0, ProtocolError::InvalidAmount);
+
+    // Same transfer fee policy as `borrow`
+    let transfer_fee = current_transfer_fee(&ctx.accounts.mint.to_account_info())?;
+    if ctx.accounts.pool.transfer_fee_mode == TransferFeeMode::Refuse {
+        require!(transfer_fee.is_none(), ProtocolError::TransferFeeNotSupported);
+    }
+
+    // Same fee and amount owed as `borrow` records for the repay
+    let fee = ctx.accounts.pool.loan_fee(amount)?;
+    let amount_owed = amount.checked_add(fee).ok_or(ProtocolError::Overflow)?;
+
+    // Anchor hands the quote back to the caller through `set_return_data`
+    Ok(LoanQuote {
+        fee,
+        total: amount_to_send(transfer_fee.as_ref(), amount_owed)?,
+        available: ctx.accounts.pool.max_loan(ctx.accounts.vault.amount),
+    })
+  }
+
+  pub fn quote_sol(ctx: Context<QuoteSol>, amount: u64) -> Result<LoanQuote> {
+    require!(amount > 0, ProtocolError::InvalidAmount);
+
+    // Same fee and amount owed as `borrow_sol` records, lamports carry no transfer fee
+    let fee = ctx.accounts.pool.loan_fee(amount)?;
+
+    Ok(LoanQuote {
+        fee,
+        total: amount.checked_add(fee).ok_or(ProtocolError::Overflow)?,
+        available: ctx.accounts.pool.max_loan(available_lamports(&ctx.accounts.vault)?),
+    })
+  }
+
+  pub fn deposit(ctx: Context<Liquidity>, amount: u64) -> Result<()> {
+    require!(amount > 0, ProtocolError::InvalidAmount);
+    // The vault is short of the lent principal while a loan is open, which would misprice shares
+    require!(ctx.accounts.pool.outstanding == 0, ProtocolError::LoanInProgress);
+
+    // Price what the vault actually receives against the LP part of the vault balance
+    let transfer_fee = current_transfer_fee(&ctx.accounts.mint.to_account_info())?;
+    let received = amount_received(transfer_fee.as_ref(), amount)?;
+    let total_assets = ctx.accounts.pool.lp_assets(ctx.accounts.vault.amount);
+    let shares = Pool::shares_for_deposit(received, total_assets, ctx.accounts.lp_mint.supply)
+        .ok_or(ProtocolError::Overflow)?;
+    require!(shares > 0, ProtocolError::InvalidAmount);
+    // Seeding a pool with dust would make its share price cheap to inflate
+    require!(ctx.accounts.lp_mint.supply > 0 || received >= MIN_FIRST_DEPOSIT, ProtocolError::DepositTooSmall);
+
+    // Transfer the funds from the provider to the pool vault
+    transfer_checked(
+        CpiContext::new(ctx.accounts.token_program.to_account_info(), TransferChecked {
+            from: ctx.accounts.provider_ata.to_account_info(),
+            mint: ctx.accounts.mint.to_account_info(),
+            to: ctx.accounts.vault.to_account_info(),
+            authority: ctx.accounts.provider.to_account_info(),
+        }),
+        amount,
+        ctx.accounts.mint.decimals
+    )?;
+
+    let mint_key = ctx.accounts.mint.key();
+    let seeds = &[
+        POOL_SEED,
+        mint_key.as_ref(),
+        &[ctx.accounts.pool.bump]
+    ];
+    let signer_seeds = &[&seeds[..]];
+
+    // Mint the matching shares to the provider
+    mint_to(
+        CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            MintTo {
+                mint: ctx.accounts.lp_mint.to_account_info(),
+                to: ctx.accounts.provider_lp_ata.to_account_info(),
+                authority: ctx.accounts.pool.to_account_info(),
+            },
+            signer_seeds
+        ),
+        shares
+    )?;
+
+    Ok(())
+  }
+
+  pub fn withdraw(ctx: Context<Liquidity>, shares: u64) -> Result<()> {
+    require!(shares > 0, ProtocolError::InvalidAmount);
+    require!(ctx.accounts.pool.outstanding == 0, ProtocolError::LoanInProgress);
+
+    // Redeem the shares at the current exchange rate, LP fees included
+    let total_assets = ctx.accounts.pool.lp_assets(ctx.accounts.vault.amount);
+    let amount = Pool::assets_for_shares(shares, total_assets, ctx.accounts.lp_mint.supply)
+        .ok_or(ProtocolError::Overflow)?;
+    require!(amount > 0, ProtocolError::InvalidAmount);
+
+    // Burn the shares from the provider
+    burn(
+        CpiContext::new(ctx.accounts.token_program.to_account_info(), Burn {
+            mint: ctx.accounts.lp_mint.to_account_info(),
+            from: ctx.accounts.provider_lp_ata.to_account_info(),
+            authority: ctx.accounts.provider.to_account_info(),
+        }),
+        shares
+    )?;
+
+    let mint_key = ctx.accounts.mint.key();
+    let seeds = &[
+        POOL_SEED,
+        mint_key.as_ref(),
+        &[ctx.accounts.pool.bump]
+    ];
+    let signer_seeds = &[&seeds[..]];
+
+    // Transfer the funds from the pool vault to the provider
+    transfer_checked(
+        CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            TransferChecked {
+                from: ctx.accounts.vault.to_account_info(),
+                mint: ctx.accounts.mint.to_account_info(),
+                to: ctx.accounts.provider_ata.to_account_info(),
+                authority: ctx.accounts.pool.to_account_info(),
+            },
+            signer_seeds
+        ),
+        amount,
+        ctx.accounts.mint.decimals
+    )?;
+
+    Ok(())
+  }
+
+  pub fn collect_protocol_fees(ctx: Context<CollectProtocolFees>) -> Result<()> {
+    // The vault is short of the lent principal while a loan is open
+    require!(ctx.accounts.pool.outstanding == 0, ProtocolError::LoanInProgress);
+
+    let amount = ctx.accounts.pool.unclaimed_protocol_fees;
+    require!(amount > 0, ProtocolError::InvalidAmount);
+
+    let mint_key = ctx.accounts.mint.key();
+    let seeds = &[
+        POOL_SEED,
+        mint_key.as_ref(),
+        &[ctx.accounts.pool.bump]
+    ];
+    let signer_seeds = &[&seeds[..]];
+
+    // Transfer the protocol fees from the pool vault to the treasury, which bears any mint transfer fee
+    transfer_checked(
+        CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            TransferChecked {
+                from: ctx.accounts.vault.to_account_info(),
+                mint: ctx.accounts.mint.to_account_info(),
+                to: ctx.accounts.treasury_ata.to_account_info(),
+                authority: ctx.accounts.pool.to_account_info(),
+            },
+            signer_seeds
+        ),
+        amount,
+        ctx.accounts.mint.decimals
+    )?;
+
+    ctx.accounts.pool.unclaimed_protocol_fees = 0;
+
+    Ok(())
+  }
  
-  pub fn borrow(ctx: Context<Loan>, borrow_amount: u64) -> Result<()> {
+  pub fn borrow(ctx: Context<Borrow>, borrow_amount: u64) -> Result<()> {
+    // Circuit breaker, repays keep working so open loans can still settle
+    require!(!ctx.accounts.config.paused, ProtocolError::Paused);
+
     // Make sure we're not sending in an invalid amount that can crash our Protocol
     require!(borrow_amount > 0, ProtocolError::InvalidAmount);
 
-    // Derive the Signer Seeds for the Protocol Account
+    // Transfer fee mints can only be lent by pools grossing up the repay
+    if ctx.accounts.pool.transfer_fee_mode == TransferFeeMode::Refuse {
+        require!(current_transfer_fee(&ctx.accounts.mint.to_account_info())?.is_none(), ProtocolError::TransferFeeNotSupported);
+    }
+
+    // Respect the per-loan and utilization caps of the pool
+    ctx.accounts.pool.check_borrow_caps(borrow_amount, ctx.accounts.vault.amount)?;
+
+    // A borrower can only have one open loan per pool
+    require!(ctx.accounts.loan.principal == 0, ProtocolError::LoanInProgress);
+
+    // Fix the fee now, the repay charges what the receipt records
+    let fee = ctx.accounts.pool.loan_fee(borrow_amount)?;
+
+    // Record the vault balance the repay has to restore, the repay index is set once the repay is found
+    ctx.accounts.loan.set_inner(LoanReceipt {
+        pre_balance: ctx.accounts.vault.amount,
+        principal: borrow_amount,
+        fee,
+        caller: None,
+        repay_index: 0,
+        bump: ctx.bumps.loan,
+    });
+
+    // Derive the Signer Seeds for the Pool Account
+    let mint_key = ctx.accounts.mint.key();
     let seeds = &[
-        b"protocol".as_ref(),
-        &[ctx.bumps.protocol]
+        POOL_SEED,
+        mint_key.as_ref(),
+        &[ctx.accounts.pool.bump]
     ];
     let signer_seeds = &[&seeds[..]];
 
-    // Transfer the funds from the protocol to the borrower
-    transfer(
+    // Transfer the funds from the pool vault to the borrower
+    transfer_checked(
         CpiContext::new_with_signer(
             ctx.accounts.token_program.to_account_info(),
-            Transfer {
-                from: ctx.accounts.protocol_ata.to_account_info(),
+            TransferChecked {
+                from: ctx.accounts.vault.to_account_info(),
+                mint: ctx.accounts.mint.to_account_info(),
                 to: ctx.accounts.borrower_ata.to_account_info(),
-                authority: ctx.accounts.protocol.to_account_info(),
+                authority: ctx.accounts.pool.to_account_info(),
             },
             signer_seeds
         ),
-        borrow_amount
+        borrow_amount,
+        ctx.accounts.mint.decimals
     )?;
 
+    // Keep track of the principal until it gets repaid
+    let pool = &mut ctx.accounts.pool;
+    pool.outstanding = pool.outstanding.checked_add(borrow_amount).ok_or(ProtocolError::Overflow)?;
+
+    emit!(LoanOpened {
+        borrower: ctx.accounts.borrower.key(),
+        mint: mint_key,
+        amount: borrow_amount,
+        fee_bps: pool.fee_bps,
+        slot: Clock::get()?.slot,
+    });
+
     /*
         Instruction Introspection 
         This is the primary means by which we secure our program,
@@ -53,94 +423,1007 @@ pub mod blueshift_anchor_flash_loan {
     */
     let ixs = ctx.accounts.instructions.to_account_info();
 
-    /*
-        Repay Instruction Check 
-        Make sure that the last instruction of this transaction is a repay instruction
-    */
-    // Check if this is the first instruction in the transaction.
-    let current_index = load_current_index_checked(&ctx.accounts.instructions)?;
-    require_eq!(current_index, 0, ProtocolError::InvalidIx);
-
-    // Check how many instruction we have in this transaction
     let instruction_sysvar = ixs.try_borrow_data()?;
-    let len = u16::from_le_bytes(instruction_sysvar[0..2].try_into().unwrap());
-
-    // Ensure we have a repay ix
-    if let Ok(repay_ix) = load_instruction_at_checked(len as usize - 1, &ixs) {
-        // Instruction checks
-        require_keys_eq!(repay_ix.program_id, ID, ProtocolError::InvalidProgram);
-        require!(repay_ix.data[0..8].eq(instruction::Repay::DISCRIMINATOR), ProtocolError::InvalidIx);
-
-        // We could check the Wallet and Mint separately but by checking the ATA we do this automatically
-        require_keys_eq!(repay_ix.accounts.get(3).ok_or(ProtocolError::InvalidBorrowerAta)?.pubkey, ctx.accounts.borrower_ata.key(), ProtocolError::InvalidBorrowerAta);
-        require_keys_eq!(repay_ix.accounts.get(4).ok_or(ProtocolError::InvalidProtocolAta)?.pubkey, ctx.accounts.protocol_ata.key(), ProtocolError::InvalidProtocolAta);
-    } else {
-        return Err(ProtocolError::MissingRepayIx.into());
+    let sysvar = InstructionsSysvar::new(&instruction_sysvar)?;
+
+    match invocation(&sysvar, get_stack_height())? {
+        Invocation::TopLevel => {
+            /*
+                Repay Instruction Check 
+                Pair every borrow of the transaction with its repay, make sure that a
+                repay instruction settles this borrow and that only whitelisted
+                instructions (compute budget, memo, ...) run before the first loan
+            */
+            let pair = validate_borrow(&sysvar, &ctx.accounts.config.allowed_leading_programs)?;
+            let repay_ix = pair.repay;
+
+            // We could check the Wallet and Mint separately but by checking the ATA we do this automatically
+            require_keys_eq!(account_key(&repay_ix, BORROWER_ATA_INDEX).ok_or(ProtocolError::InvalidBorrowerAta)?, ctx.accounts.borrower_ata.key(), ProtocolError::InvalidBorrowerAta);
+            require_keys_eq!(account_key(&repay_ix, VAULT_INDEX).ok_or(ProtocolError::InvalidVault)?, ctx.accounts.vault.key(), ProtocolError::InvalidVault);
+
+            // Only that repay can close the receipt
+            ctx.accounts.loan.repay_index = pair.repay_index as u16;
+        }
+        Invocation::Cpi { caller, index } => {
+            /*
+                CPI Borrow
+                Inner instructions are not visible in the sysvar, so the repay cannot
+                be checked here. Only allowed programs can borrow, and a later
+                top-level `check_repaid` of the receipt must fail the transaction if
+                the caller did not repay from the same top-level instruction.
+            */
+            require!(ctx.accounts.config.allowed_cpi_callers.contains(&caller), ProtocolError::UnauthorizedCaller);
+            find_repay_check(&sysvar, index, &ctx.accounts.loan.key())?;
+
+            ctx.accounts.loan.caller = Some(caller);
+            ctx.accounts.loan.repay_index = index as u16;
+        }
     }
 
     Ok(())
   }
  
-  pub fn repay(ctx: Context<Loan>) -> Result<()> {
+  pub fn repay(ctx: Context<Repay>) -> Result<()> {
     let ixs = ctx.accounts.instructions.to_account_info();
 
-    let mut amount_borrowed: u64;
-    if let Ok(borrow_ix) = load_instruction_at_checked(0, &ixs) {
-        // Check the amount borrowed:
-        let mut borrowed_data: [u8;8] = [0u8;8];
-        borrowed_data.copy_from_slice(&borrow_ix.data[8..16]);
-        amount_borrowed = u64::from_le_bytes(borrowed_data)
-    } else {
-        return Err(ProtocolError::MissingBorrowIx.into());
+    {
+        let instruction_sysvar = ixs.try_borrow_data()?;
+        let sysvar = InstructionsSysvar::new(&instruction_sysvar)?;
+
+        match invocation(&sysvar, get_stack_height())? {
+            Invocation::TopLevel => {
+                // A loan opened through a CPI can only be settled by its caller
+                require!(ctx.accounts.loan.caller.is_none(), ProtocolError::CallerMismatch);
+
+                // The receipt must be closed by the repay its borrow was paired with
+                require!(ctx.accounts.loan.repay_index as usize == sysvar.current_index(), ProtocolError::RepayIndexMismatch);
+
+                // Find the borrow this repay settles
+                let borrow_ix = find_paired_borrow(&sysvar, sysvar.current_index())?.borrow;
+
+                // The borrow must have lent from this vault to this borrower ATA
+                require_keys_eq!(account_key(&borrow_ix, BORROWER_ATA_INDEX).ok_or(ProtocolError::BorrowAccountMismatch)?, ctx.accounts.borrower_ata.key(), ProtocolError::BorrowAccountMismatch);
+                require_keys_eq!(account_key(&borrow_ix, VAULT_INDEX).ok_or(ProtocolError::BorrowAccountMismatch)?, ctx.accounts.vault.key(), ProtocolError::BorrowAccountMismatch);
+            }
+            Invocation::Cpi { caller, index } => {
+                // Only the program that borrowed can repay, from the same top-level instruction
+                require!(ctx.accounts.loan.caller == Some(caller), ProtocolError::CallerMismatch);
+                require!(ctx.accounts.loan.repay_index as usize == index, ProtocolError::RepayIndexMismatch);
+            }
+        }
     }
 
-    // Add the fee to the amount borrowed (In our case we hardcoded it to 500 basis point)
-    let fee = (amount_borrowed as u128).checked_mul(500).unwrap().checked_div(10_000).ok_or(ProtocolError::Overflow)? as u64;
-    amount_borrowed = amount_borrowed.checked_add(fee).ok_or(ProtocolError::Overflow)?;
+    // The amount owed comes from the loan receipt recorded by the borrow
+    let pre_balance = ctx.accounts.loan.pre_balance;
+    let mut amount_borrowed = ctx.accounts.loan.principal;
+    require!(amount_borrowed > 0, ProtocolError::MissingBorrowIx);
+
+    // Add the fee fixed by the borrow to the amount borrowed
+    let principal = amount_borrowed;
+    let fee = ctx.accounts.loan.fee;
+    amount_borrowed = principal.checked_add(fee).ok_or(ProtocolError::Overflow)?;
+
+    // Send enough for the vault to net the amount owed after the mint transfer fee
+    let transfer_fee = current_transfer_fee(&ctx.accounts.mint.to_account_info())?;
+    amount_borrowed = amount_to_send(transfer_fee.as_ref(), amount_borrowed)?;
 
-    // Transfer the funds from the borrower back to the protocol
-    transfer(
-        CpiContext::new(ctx.accounts.token_program.to_account_info(), Transfer {
+    // Transfer the funds from the borrower back to the pool vault
+    transfer_checked(
+        CpiContext::new(ctx.accounts.token_program.to_account_info(), TransferChecked {
             from: ctx.accounts.borrower_ata.to_account_info(),
-            to: ctx.accounts.protocol_ata.to_account_info(),
+            mint: ctx.accounts.mint.to_account_info(),
+            to: ctx.accounts.vault.to_account_info(),
             authority: ctx.accounts.borrower.to_account_info(),
         }),
-        amount_borrowed
+        amount_borrowed,
+        ctx.accounts.mint.decimals
+    )?;
+
+    // The vault must hold at least what it had before the loan, plus the fee
+    ctx.accounts.vault.reload()?;
+    let expected_balance = pre_balance.checked_add(fee).ok_or(ProtocolError::Overflow)?;
+    require_gte!(ctx.accounts.vault.amount, expected_balance, ProtocolError::NotEnoughFunds);
+
+    // The loan is settled, close the receipt and refund its rent to the borrower
+    ctx.accounts.loan.close(ctx.accounts.borrower.to_account_info())?;
+
+    // Update the pool statistics, splitting the fee between the liquidity providers and the protocol
+    let pool = &mut ctx.accounts.pool;
+    pool.outstanding = pool.outstanding.checked_sub(principal).ok_or(ProtocolError::Overflow)?;
+    pool.total_borrowed = pool.total_borrowed.checked_add(principal).ok_or(ProtocolError::Overflow)?;
+    pool.accrue_fee(fee, ctx.accounts.config.protocol_share_bps)?;
+    pool.loan_count = pool.loan_count.checked_add(1).ok_or(ProtocolError::Overflow)?;
+
+    emit!(LoanRepaid {
+        borrower: ctx.accounts.borrower.key(),
+        mint: ctx.accounts.mint.key(),
+        principal,
+        fee,
+        slot: Clock::get()?.slot,
+    });
+
+    Ok(())
+  }
+
+  pub fn check_repaid(ctx: Context<CheckRepaid>) -> Result<()> {
+    // Borrows only look for the check among the top-level instructions
+    {
+        let instruction_sysvar = ctx.accounts.instructions.try_borrow_data()?;
+        let sysvar = InstructionsSysvar::new(&instruction_sysvar)?;
+        require!(invocation(&sysvar, get_stack_height())? == Invocation::TopLevel, ProtocolError::UnauthorizedCaller);
+    }
+
+    // Loans opened through a CPI must be followed by this check, which fails
+    // while any of the receipts passed as remaining accounts is open
+    for receipt in ctx.remaining_accounts {
+        // Repays close the receipt, handing it back to the system program
+        require!(receipt.owner != &ID || receipt.data_is_empty(), ProtocolError::LoanNotRepaid);
+    }
+
+    Ok(())
+  }
+
+  pub fn flash_loan<'info>(
+    ctx: Context<'_, '_, 'info, 'info, FlashLoan<'info>>,
+    amount: u64,
+    data: Vec<u8>,
+  ) -> Result<()> {
+    require!(!ctx.accounts.config.paused, ProtocolError::Paused);
+    require!(amount > 0, ProtocolError::InvalidAmount);
+    // The callback must run in another program, this one has no `on_flash_loan`
+    require_keys_neq!(ctx.accounts.receiver_program.key(), ID, ProtocolError::InvalidReceiver);
+
+    if ctx.accounts.pool.transfer_fee_mode == TransferFeeMode::Refuse {
+        require!(current_transfer_fee(&ctx.accounts.mint.to_account_info())?.is_none(), ProtocolError::TransferFeeNotSupported);
+    }
+    ctx.accounts.pool.check_borrow_caps(amount, ctx.accounts.vault.amount)?;
+
+    let pre_balance = ctx.accounts.vault.amount;
+    let fee = ctx.accounts.pool.loan_fee(amount)?;
+
+    let mint_key = ctx.accounts.mint.key();
+    let seeds = &[
+        POOL_SEED,
+        mint_key.as_ref(),
+        &[ctx.accounts.pool.bump]
+    ];
+    let signer_seeds = &[&seeds[..]];
+
+    // Transfer the funds from the pool vault to the receiver
+    transfer_checked(
+        CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            TransferChecked {
+                from: ctx.accounts.vault.to_account_info(),
+                mint: ctx.accounts.mint.to_account_info(),
+                to: ctx.accounts.receiver_token_account.to_account_info(),
+                authority: ctx.accounts.pool.to_account_info(),
+            },
+            signer_seeds
+        ),
+        amount,
+        ctx.accounts.mint.decimals
+    )?;
+
+    emit!(LoanOpened {
+        borrower: ctx.accounts.borrower.key(),
+        mint: mint_key,
+        amount,
+        fee_bps: ctx.accounts.pool.fee_bps,
+        slot: Clock::get()?.slot,
+    });
+
+    /*
+        Callback
+        The receiver runs with the funds and has to send them back with the fee.
+        It is invoked without the pool seeds, so it can never move the vault
+        funds itself, and the runtime refuses any reentrancy into this program.
+    */
+    let mut accounts = vec![
+        AccountMeta::new_readonly(ctx.accounts.borrower.key(), true),
+        AccountMeta::new_readonly(mint_key, false),
+        AccountMeta::new(ctx.accounts.vault.key(), false),
+        AccountMeta::new(ctx.accounts.receiver_token_account.key(), false),
+        AccountMeta::new_readonly(ctx.accounts.token_program.key(), false),
+    ];
+    accounts.extend(ctx.remaining_accounts.iter().map(|account| AccountMeta {
+        pubkey: account.key(),
+        is_signer: account.is_signer,
+        is_writable: account.is_writable,
+    }));
+    let callback = OnFlashLoan {
+        initiator: ctx.accounts.borrower.key(),
+        mint: mint_key,
+        amount,
+        fee,
+        data,
+    }
+    .instruction(ctx.accounts.receiver_program.key(), accounts);
+
+    let mut account_infos = vec![
+        ctx.accounts.borrower.to_account_info(),
+        ctx.accounts.mint.to_account_info(),
+        ctx.accounts.vault.to_account_info(),
+        ctx.accounts.receiver_token_account.to_account_info(),
+        ctx.accounts.token_program.to_account_info(),
+        ctx.accounts.receiver_program.to_account_info(),
+    ];
+    account_infos.extend_from_slice(ctx.remaining_accounts);
+    invoke(&callback, &account_infos)?;
+
+    // The vault must hold at least what it had before the loan, plus the fee
+    ctx.accounts.vault.reload()?;
+    let expected_balance = pre_balance.checked_add(fee).ok_or(ProtocolError::Overflow)?;
+    require_gte!(ctx.accounts.vault.amount, expected_balance, ProtocolError::NotEnoughFunds);
+
+    // Update the pool statistics, the loan never stays open past this instruction
+    let pool = &mut ctx.accounts.pool;
+    pool.total_borrowed = pool.total_borrowed.checked_add(amount).ok_or(ProtocolError::Overflow)?;
+    pool.accrue_fee(fee, ctx.accounts.config.protocol_share_bps)?;
+    pool.loan_count = pool.loan_count.checked_add(1).ok_or(ProtocolError::Overflow)?;
+
+    emit!(LoanRepaid {
+        borrower: ctx.accounts.borrower.key(),
+        mint: mint_key,
+        principal: amount,
+        fee,
+        slot: Clock::get()?.slot,
+    });
+
+    Ok(())
+  }
+
+  pub fn initialize_sol_pool(ctx: Context<InitializeSolPool>) -> Result<()> {
+    ctx.accounts.pool.set_inner(Pool {
+        mint: SOL_POOL_MINT,
+        vault: ctx.accounts.vault.key(),
+        lp_mint: ctx.accounts.lp_mint.key(),
+        fee_bps: ctx.accounts.config.fee_bps,
+        fee_rounding: Rounding::Up,
+        min_fee: 0,
+        max_borrow: 0,
+        max_utilization_bps: 0,
+        transfer_fee_mode: TransferFeeMode::Refuse,
+        outstanding: 0,
+        total_borrowed: 0,
+        lp_fees: 0,
+        protocol_fees: 0,
+        unclaimed_protocol_fees: 0,
+        loan_count: 0,
+        bump: ctx.bumps.pool,
+    });
+    ctx.accounts.vault.bump = ctx.bumps.vault;
+
+    Ok(())
+  }
+
+  pub fn deposit_sol(ctx: Context<SolLiquidity>, amount: u64) -> Result<()> {
+    require!(amount > 0, ProtocolError::InvalidAmount);
+    require!(ctx.accounts.pool.outstanding == 0, ProtocolError::LoanInProgress);
+
+    // Price the deposit against the lamports currently available in the vault, unclaimed protocol fees excluded
+    let available = ctx.accounts.pool.lp_assets(available_lamports(&ctx.accounts.vault)?);
+    let shares = Pool::shares_for_deposit(amount, available, ctx.accounts.lp_mint.supply)
+        .ok_or(ProtocolError::Overflow)?;
+    require!(shares > 0, ProtocolError::InvalidAmount);
+    require!(ctx.accounts.lp_mint.supply > 0 || amount >= MIN_FIRST_DEPOSIT, ProtocolError::DepositTooSmall);
+
+    // Transfer the lamports from the provider to the pool vault
+    system_transfer(
+        CpiContext::new(ctx.accounts.system_program.to_account_info(), SystemTransfer {
+            from: ctx.accounts.provider.to_account_info(),
+            to: ctx.accounts.vault.to_account_info(),
+        }),
+        amount
+    )?;
+
+    let seeds = &[
+        POOL_SEED,
+        SOL_POOL_MINT.as_ref(),
+        &[ctx.accounts.pool.bump]
+    ];
+    let signer_seeds = &[&seeds[..]];
+
+    // Mint the matching shares to the provider
+    mint_to(
+        CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            MintTo {
+                mint: ctx.accounts.lp_mint.to_account_info(),
+                to: ctx.accounts.provider_lp_ata.to_account_info(),
+                authority: ctx.accounts.pool.to_account_info(),
+            },
+            signer_seeds
+        ),
+        shares
+    )?;
+
+    Ok(())
+  }
+
+  pub fn withdraw_sol(ctx: Context<SolLiquidity>, shares: u64) -> Result<()> {
+    require!(shares > 0, ProtocolError::InvalidAmount);
+    require!(ctx.accounts.pool.outstanding == 0, ProtocolError::LoanInProgress);
+
+    // Redeem the shares at the current exchange rate, LP fees included
+    let available = ctx.accounts.pool.lp_assets(available_lamports(&ctx.accounts.vault)?);
+    let amount = Pool::assets_for_shares(shares, available, ctx.accounts.lp_mint.supply)
+        .ok_or(ProtocolError::Overflow)?;
+    require!(amount > 0, ProtocolError::InvalidAmount);
+
+    // Burn the shares from the provider
+    burn(
+        CpiContext::new(ctx.accounts.token_program.to_account_info(), Burn {
+            mint: ctx.accounts.lp_mint.to_account_info(),
+            from: ctx.accounts.provider_lp_ata.to_account_info(),
+            authority: ctx.accounts.provider.to_account_info(),
+        }),
+        shares
+    )?;
+
+    // The vault is owned by this program, so its lamports are moved directly
+    ctx.accounts.vault.sub_lamports(amount)?;
+    ctx.accounts.provider.add_lamports(amount)?;
+
+    Ok(())
+  }
+
+  pub fn collect_sol_protocol_fees(ctx: Context<CollectSolProtocolFees>) -> Result<()> {
+    require!(ctx.accounts.pool.outstanding == 0, ProtocolError::LoanInProgress);
+
+    let amount = ctx.accounts.pool.unclaimed_protocol_fees;
+    require!(amount > 0, ProtocolError::InvalidAmount);
+
+    ctx.accounts.vault.sub_lamports(amount)?;
+    ctx.accounts.treasury.add_lamports(amount)?;
+
+    ctx.accounts.pool.unclaimed_protocol_fees = 0;
+
+    Ok(())
+  }
+
+  pub fn borrow_sol(ctx: Context<BorrowSol>, borrow_amount: u64) -> Result<()> {
+    require!(!ctx.accounts.config.paused, ProtocolError::Paused);
+    require!(borrow_amount > 0, ProtocolError::InvalidAmount);
+
+    let available = available_lamports(&ctx.accounts.vault)?;
+    ctx.accounts.pool.check_borrow_caps(borrow_amount, available)?;
+    require_gte!(available, borrow_amount, ProtocolError::NotEnoughFunds);
+
+    require!(ctx.accounts.loan.principal == 0, ProtocolError::LoanInProgress);
+
+    let fee = ctx.accounts.pool.loan_fee(borrow_amount)?;
+
+    // Record the vault balance the repay has to restore
+    ctx.accounts.loan.set_inner(LoanReceipt {
+        pre_balance: available,
+        principal: borrow_amount,
+        fee,
+        caller: None,
+        repay_index: 0,
+        bump: ctx.bumps.loan,
+    });
+
+    // Move the lamports from the pool vault to the borrower
+    ctx.accounts.vault.sub_lamports(borrow_amount)?;
+    ctx.accounts.borrower.add_lamports(borrow_amount)?;
+
+    let pool = &mut ctx.accounts.pool;
+    pool.outstanding = pool.outstanding.checked_add(borrow_amount).ok_or(ProtocolError::Overflow)?;
+
+    emit!(LoanOpened {
+        borrower: ctx.accounts.borrower.key(),
+        mint: SOL_POOL_MINT,
+        amount: borrow_amount,
+        fee_bps: pool.fee_bps,
+        slot: Clock::get()?.slot,
+    });
+
+    // Same introspection as token loans, SOL loans are paired on the vault PDA
+    let ixs = ctx.accounts.instructions.to_account_info();
+    let instruction_sysvar = ixs.try_borrow_data()?;
+    let sysvar = InstructionsSysvar::new(&instruction_sysvar)?;
+
+    // SOL loans are top-level only
+    require!(invocation(&sysvar, get_stack_height())? == Invocation::TopLevel, ProtocolError::UnauthorizedCaller);
+
+    let pair = validate_borrow(&sysvar, &ctx.accounts.config.allowed_leading_programs)?;
+    let repay_ix = pair.repay;
+
+    // The loan receipt PDA is derived from the borrower, so matching it matches the borrower
+    require_keys_eq!(account_key(&repay_ix, SOL_LOAN_INDEX).ok_or(ProtocolError::InvalidBorrower)?, ctx.accounts.loan.key(), ProtocolError::InvalidBorrower);
+    require_keys_eq!(account_key(&repay_ix, VAULT_INDEX).ok_or(ProtocolError::InvalidVault)?, ctx.accounts.vault.key(), ProtocolError::InvalidVault);
+
+    ctx.accounts.loan.repay_index = pair.repay_index as u16;
+
+    Ok(())
+  }
+
+  pub fn repay_sol(ctx: Context<RepaySol>) -> Result<()> {
+    let ixs = ctx.accounts.instructions.to_account_info();
+
+    {
+        let instruction_sysvar = ixs.try_borrow_data()?;
+        let sysvar = InstructionsSysvar::new(&instruction_sysvar)?;
+
+        require!(invocation(&sysvar, get_stack_height())? == Invocation::TopLevel, ProtocolError::UnauthorizedCaller);
+        require!(ctx.accounts.loan.repay_index as usize == sysvar.current_index(), ProtocolError::RepayIndexMismatch);
+
+        let borrow_ix = find_paired_borrow(&sysvar, sysvar.current_index())?.borrow;
+
+        require_keys_eq!(account_key(&borrow_ix, SOL_LOAN_INDEX).ok_or(ProtocolError::BorrowAccountMismatch)?, ctx.accounts.loan.key(), ProtocolError::BorrowAccountMismatch);
+        require_keys_eq!(account_key(&borrow_ix, VAULT_INDEX).ok_or(ProtocolError::BorrowAccountMismatch)?, ctx.accounts.vault.key(), ProtocolError::BorrowAccountMismatch);
+    }
+
+    let pre_balance = ctx.accounts.loan.pre_balance;
+    let principal = ctx.accounts.loan.principal;
+    require!(principal > 0, ProtocolError::MissingBorrowIx);
+
+    let fee = ctx.accounts.loan.fee;
+    let amount_owed = principal.checked_add(fee).ok_or(ProtocolError::Overflow)?;
+
+    // Transfer the lamports from the borrower back to the pool vault
+    system_transfer(
+        CpiContext::new(ctx.accounts.system_program.to_account_info(), SystemTransfer {
+            from: ctx.accounts.borrower.to_account_info(),
+            to: ctx.accounts.vault.to_account_info(),
+        }),
+        amount_owed
     )?;
 
+    let expected_balance = pre_balance.checked_add(fee).ok_or(ProtocolError::Overflow)?;
+    require_gte!(available_lamports(&ctx.accounts.vault)?, expected_balance, ProtocolError::NotEnoughFunds);
+
+    ctx.accounts.loan.close(ctx.accounts.borrower.to_account_info())?;
+
+    let pool = &mut ctx.accounts.pool;
+    pool.outstanding = pool.outstanding.checked_sub(principal).ok_or(ProtocolError::Overflow)?;
+    pool.total_borrowed = pool.total_borrowed.checked_add(principal).ok_or(ProtocolError::Overflow)?;
+    pool.accrue_fee(fee, ctx.accounts.config.protocol_share_bps)?;
+    pool.loan_count = pool.loan_count.checked_add(1).ok_or(ProtocolError::Overflow)?;
+
+    emit!(LoanRepaid {
+        borrower: ctx.accounts.borrower.key(),
+        mint: SOL_POOL_MINT,
+        principal,
+        fee,
+        slot: Clock::get()?.slot,
+    });
+
     Ok(())
   }
 }
  
+/// Lamports of the SOL pool vault above its rent-exempt minimum
+fn available_lamports(vault: &Account<SolVault>) -> Result<u64> {
+  let rent = Rent::get()?.minimum_balance(8 + SolVault::INIT_SPACE);
+  Ok(vault.get_lamports().saturating_sub(rent))
+}
+
+/// Transfer fee `mint` charges in the current epoch, if any
+fn current_transfer_fee(mint: &AccountInfo) -> Result<Option<TransferFee>> {
+  let data = mint.try_borrow_data()?;
+  mint_transfer_fee(mint.owner, &data, Clock::get()?.epoch)
+}
+
 #[derive(Accounts)]
-pub struct Loan<'info> {
+pub struct Borrow<'info> {
   #[account(mut)]
   pub borrower: Signer<'info>,
   #[account(
-    seeds = [b"protocol".as_ref()],
-    bump,
+    mut,
+    seeds = [POOL_SEED, mint.key().as_ref()],
+    bump = pool.bump,
   )]
-  pub protocol: SystemAccount<'info>,
+  pub pool: Account<'info, Pool>,
  
-  pub mint: Account<'info, Mint>,
+  pub mint: InterfaceAccount<'info, Mint>,
+  #[account(
+    init_if_needed,
+    payer = borrower,
+    associated_token::mint = mint,
+    associated_token::authority = borrower,
+    associated_token::token_program = token_program,
+  )]
+  pub borrower_ata: InterfaceAccount<'info, TokenAccount>,
+  #[account(
+    mut,
+    associated_token::mint = mint,
+    associated_token::authority = pool,
+    associated_token::token_program = token_program,
+  )]
+  pub vault: InterfaceAccount<'info, TokenAccount>,
+  #[account(
+    seeds = [CONFIG_SEED],
+    bump = config.bump,
+  )]
+  pub config: Account<'info, ProtocolConfig>,
   #[account(
     init_if_needed,
     payer = borrower,
+    space = 8 + LoanReceipt::INIT_SPACE,
+    seeds = [LOAN_SEED, pool.key().as_ref(), borrower.key().as_ref()],
+    bump,
+  )]
+  pub loan: Account<'info, LoanReceipt>,
+ 
+  #[account(address = INSTRUCTIONS_SYSVAR_ID)]
+  /// CHECK: InstructionsSysvar account
+  instructions: UncheckedAccount<'info>,
+  pub token_program: Interface<'info, TokenInterface>,
+  pub associated_token_program: Program<'info, AssociatedToken>,
+  pub system_program: Program<'info, System>
+}
+
+/// The borrower ATA and vault sit at the same positions as in `Borrow`, borrows and repays are paired on them
+#[derive(Accounts)]
+pub struct Repay<'info> {
+  #[account(mut)]
+  pub borrower: Signer<'info>,
+  #[account(
+    mut,
+    seeds = [POOL_SEED, mint.key().as_ref()],
+    bump = pool.bump,
+  )]
+  pub pool: Account<'info, Pool>,
+ 
+  pub mint: InterfaceAccount<'info, Mint>,
+  #[account(
+    mut,
     associated_token::mint = mint,
     associated_token::authority = borrower,
+    associated_token::token_program = token_program,
+  )]
+  pub borrower_ata: InterfaceAccount<'info, TokenAccount>,
+  #[account(
+    mut,
+    associated_token::mint = mint,
+    associated_token::authority = pool,
+    associated_token::token_program = token_program,
+  )]
+  pub vault: InterfaceAccount<'info, TokenAccount>,
+  #[account(
+    seeds = [CONFIG_SEED],
+    bump = config.bump,
+  )]
+  pub config: Account<'info, ProtocolConfig>,
+  #[account(
+    mut,
+    seeds = [LOAN_SEED, pool.key().as_ref(), borrower.key().as_ref()],
+    bump = loan.bump,
+  )]
+  pub loan: Account<'info, LoanReceipt>,
+ 
+  #[account(address = INSTRUCTIONS_SYSVAR_ID)]
+  /// CHECK: InstructionsSysvar account
+  instructions: UncheckedAccount<'info>,
+  pub token_program: Interface<'info, TokenInterface>,
+}
+
+/// The loan receipts to check are passed as remaining accounts
+#[derive(Accounts)]
+pub struct CheckRepaid<'info> {
+  #[account(address = INSTRUCTIONS_SYSVAR_ID)]
+  /// CHECK: InstructionsSysvar account
+  instructions: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeProtocol<'info> {
+  #[account(mut)]
+  pub admin: Signer<'info>,
+  #[account(
+    init,
+    payer = admin,
+    space = 8 + ProtocolConfig::INIT_SPACE,
+    seeds = [CONFIG_SEED],
+    bump,
+  )]
+  pub config: Account<'info, ProtocolConfig>,
+  #[account(constraint = program.programdata_address()? == Some(program_data.key()))]
+  pub program: Program<'info, program::BlueshiftAnchorFlashLoan>,
+  /// Only the upgrade authority of the program can create the config, so nobody can front-run the deployer
+  #[account(constraint = program_data.upgrade_authority_address == Some(admin.key()) @ ProtocolError::Unauthorized)]
+  pub program_data: Account<'info, ProgramData>,
+  pub system_program: Program<'info, System>
+}
+
+#[derive(Accounts)]
+pub struct AdminOnly<'info> {
+  pub admin: Signer<'info>,
+  #[account(
+    mut,
+    seeds = [CONFIG_SEED],
+    bump = config.bump,
+    has_one = admin @ ProtocolError::Unauthorized,
+  )]
+  pub config: Account<'info, ProtocolConfig>,
+}
+
+#[derive(Accounts)]
+pub struct SetPaused<'info> {
+  pub authority: Signer<'info>,
+  #[account(
+    mut,
+    seeds = [CONFIG_SEED],
+    bump = config.bump,
+    constraint = authority.key() == config.admin || authority.key() == config.guardian @ ProtocolError::Unauthorized,
+  )]
+  pub config: Account<'info, ProtocolConfig>,
+}
+
+#[derive(Accounts)]
+pub struct InitializePool<'info> {
+  #[account(mut)]
+  pub admin: Signer<'info>,
+  #[account(
+    seeds = [CONFIG_SEED],
+    bump = config.bump,
+    has_one = admin @ ProtocolError::Unauthorized,
+  )]
+  pub config: Account<'info, ProtocolConfig>,
+  pub mint: InterfaceAccount<'info, Mint>,
+  #[account(
+    init,
+    payer = admin,
+    space = 8 + Pool::INIT_SPACE,
+    seeds = [POOL_SEED, mint.key().as_ref()],
+    bump,
+  )]
+  pub pool: Account<'info, Pool>,
+  #[account(
+    init,
+    payer = admin,
+    associated_token::mint = mint,
+    associated_token::authority = pool,
+    associated_token::token_program = token_program,
+  )]
+  pub vault: InterfaceAccount<'info, TokenAccount>,
+  #[account(
+    init,
+    payer = admin,
+    seeds = [LP_MINT_SEED, pool.key().as_ref()],
+    bump,
+    mint::decimals = mint.decimals,
+    mint::authority = pool,
+    mint::token_program = token_program,
+  )]
+  pub lp_mint: InterfaceAccount<'info, Mint>,
+  pub token_program: Interface<'info, TokenInterface>,
+  pub associated_token_program: Program<'info, AssociatedToken>,
+  pub system_program: Program<'info, System>
+}
+
+#[derive(Accounts)]
+pub struct Quote<'info> {
+  #[account(
+    seeds = [POOL_SEED, mint.key().as_ref()],
+    bump = pool.bump,
+  )]
+  pub pool: Account<'info, Pool>,
+  pub mint: InterfaceAccount<'info, Mint>,
+  #[account(
+    associated_token::mint = mint,
+    associated_token::authority = pool,
+    associated_token::token_program = token_program,
+  )]
+  pub vault: InterfaceAccount<'info, TokenAccount>,
+  pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct QuoteSol<'info> {
+  #[account(
+    seeds = [POOL_SEED, SOL_POOL_MINT.as_ref()],
+    bump = pool.bump,
+  )]
+  pub pool: Account<'info, Pool>,
+  #[account(
+    seeds = [SOL_VAULT_SEED, pool.key().as_ref()],
+    bump = vault.bump,
+  )]
+  pub vault: Account<'info, SolVault>,
+}
+
+#[derive(Accounts)]
+pub struct UpdatePool<'info> {
+  pub admin: Signer<'info>,
+  #[account(
+    seeds = [CONFIG_SEED],
+    bump = config.bump,
+    has_one = admin @ ProtocolError::Unauthorized,
+  )]
+  pub config: Account<'info, ProtocolConfig>,
+  #[account(
+    mut,
+    seeds = [POOL_SEED, pool.mint.as_ref()],
+    bump = pool.bump,
+  )]
+  pub pool: Account<'info, Pool>,
+}
+
+#[derive(Accounts)]
+pub struct Liquidity<'info> {
+  #[account(mut)]
+  pub provider: Signer<'info>,
+  #[account(
+    seeds = [POOL_SEED, mint.key().as_ref()],
+    bump = pool.bump,
+    has_one = lp_mint,
   )]
-  pub borrower_ata: Account<'info, TokenAccount>,
+  pub pool: Account<'info, Pool>,
+  pub mint: InterfaceAccount<'info, Mint>,
+  #[account(mut)]
+  pub lp_mint: InterfaceAccount<'info, Mint>,
   #[account(
     mut,
     associated_token::mint = mint,
-    associated_token::authority = protocol,
+    associated_token::authority = pool,
+    associated_token::token_program = token_program,
+  )]
+  pub vault: InterfaceAccount<'info, TokenAccount>,
+  #[account(
+    init_if_needed,
+    payer = provider,
+    associated_token::mint = mint,
+    associated_token::authority = provider,
+    associated_token::token_program = token_program,
+  )]
+  pub provider_ata: InterfaceAccount<'info, TokenAccount>,
+  #[account(
+    init_if_needed,
+    payer = provider,
+    associated_token::mint = lp_mint,
+    associated_token::authority = provider,
+    associated_token::token_program = token_program,
   )]
-  pub protocol_ata: Account<'info, TokenAccount>,
+  pub provider_lp_ata: InterfaceAccount<'info, TokenAccount>,
+  pub token_program: Interface<'info, TokenInterface>,
+  pub associated_token_program: Program<'info, AssociatedToken>,
+  pub system_program: Program<'info, System>
+}
  
+#[derive(Accounts)]
+pub struct CollectProtocolFees<'info> {
+  #[account(mut)]
+  pub admin: Signer<'info>,
+  #[account(
+    seeds = [CONFIG_SEED],
+    bump = config.bump,
+    has_one = admin @ ProtocolError::Unauthorized,
+    has_one = treasury @ ProtocolError::InvalidTreasury,
+  )]
+  pub config: Account<'info, ProtocolConfig>,
+  #[account(
+    mut,
+    seeds = [POOL_SEED, mint.key().as_ref()],
+    bump = pool.bump,
+  )]
+  pub pool: Account<'info, Pool>,
+  pub mint: InterfaceAccount<'info, Mint>,
+  #[account(
+    mut,
+    associated_token::mint = mint,
+    associated_token::authority = pool,
+    associated_token::token_program = token_program,
+  )]
+  pub vault: InterfaceAccount<'info, TokenAccount>,
+  /// CHECK: Treasury set in the config, only used as the owner of `treasury_ata`
+  pub treasury: UncheckedAccount<'info>,
+  #[account(
+    init_if_needed,
+    payer = admin,
+    associated_token::mint = mint,
+    associated_token::authority = treasury,
+    associated_token::token_program = token_program,
+  )]
+  pub treasury_ata: InterfaceAccount<'info, TokenAccount>,
+  pub token_program: Interface<'info, TokenInterface>,
+  pub associated_token_program: Program<'info, AssociatedToken>,
+  pub system_program: Program<'info, System>
+}
+
+#[derive(Accounts)]
+pub struct CollectSolProtocolFees<'info> {
+  pub admin: Signer<'info>,
+  #[account(
+    seeds = [CONFIG_SEED],
+    bump = config.bump,
+    has_one = admin @ ProtocolError::Unauthorized,
+    has_one = treasury @ ProtocolError::InvalidTreasury,
+  )]
+  pub config: Account<'info, ProtocolConfig>,
+  #[account(
+    mut,
+    seeds = [POOL_SEED, SOL_POOL_MINT.as_ref()],
+    bump = pool.bump,
+  )]
+  pub pool: Account<'info, Pool>,
+  #[account(
+    mut,
+    seeds = [SOL_VAULT_SEED, pool.key().as_ref()],
+    bump = vault.bump,
+  )]
+  pub vault: Account<'info, SolVault>,
+  /// CHECK: Treasury set in the config, receiving the protocol fees
+  #[account(mut)]
+  pub treasury: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct BorrowSol<'info> {
+  #[account(mut)]
+  pub borrower: Signer<'info>,
+  #[account(
+    mut,
+    seeds = [POOL_SEED, SOL_POOL_MINT.as_ref()],
+    bump = pool.bump,
+  )]
+  pub pool: Account<'info, Pool>,
+  #[account(
+    seeds = [CONFIG_SEED],
+    bump = config.bump,
+  )]
+  pub config: Account<'info, ProtocolConfig>,
+  #[account(
+    init_if_needed,
+    payer = borrower,
+    space = 8 + LoanReceipt::INIT_SPACE,
+    seeds = [LOAN_SEED, pool.key().as_ref(), borrower.key().as_ref()],
+    bump,
+  )]
+  pub loan: Account<'info, LoanReceipt>,
+  #[account(
+    mut,
+    seeds = [SOL_VAULT_SEED, pool.key().as_ref()],
+    bump = vault.bump,
+  )]
+  pub vault: Account<'info, SolVault>,
+
   #[account(address = INSTRUCTIONS_SYSVAR_ID)]
   /// CHECK: InstructionsSysvar account
   instructions: UncheckedAccount<'info>,
-  pub token_program: Program<'info, Token>,
+  pub system_program: Program<'info, System>
+}
+
+/// The loan receipt and vault sit at the same positions as in `BorrowSol`, borrows and repays are paired on them
+///
+/// The system program stays, the lamports are paid back with a system transfer.
+#[derive(Accounts)]
+pub struct RepaySol<'info> {
+  #[account(mut)]
+  pub borrower: Signer<'info>,
+  #[account(
+    mut,
+    seeds = [POOL_SEED, SOL_POOL_MINT.as_ref()],
+    bump = pool.bump,
+  )]
+  pub pool: Account<'info, Pool>,
+  #[account(
+    seeds = [CONFIG_SEED],
+    bump = config.bump,
+  )]
+  pub config: Account<'info, ProtocolConfig>,
+  #[account(
+    mut,
+    seeds = [LOAN_SEED, pool.key().as_ref(), borrower.key().as_ref()],
+    bump = loan.bump,
+  )]
+  pub loan: Account<'info, LoanReceipt>,
+  #[account(
+    mut,
+    seeds = [SOL_VAULT_SEED, pool.key().as_ref()],
+    bump = vault.bump,
+  )]
+  pub vault: Account<'info, SolVault>,
+
+  #[account(address = INSTRUCTIONS_SYSVAR_ID)]
+  /// CHECK: InstructionsSysvar account
+  instructions: UncheckedAccount<'info>,
+  pub system_program: Program<'info, System>
+}
+
+#[derive(Accounts)]
+pub struct FlashLoan<'info> {
+  pub borrower: Signer<'info>,
+  #[account(
+    mut,
+    seeds = [POOL_SEED, mint.key().as_ref()],
+    bump = pool.bump,
+  )]
+  pub pool: Account<'info, Pool>,
+  pub mint: InterfaceAccount<'info, Mint>,
+  #[account(
+    mut,
+    associated_token::mint = mint,
+    associated_token::authority = pool,
+    associated_token::token_program = token_program,
+  )]
+  pub vault: InterfaceAccount<'info, TokenAccount>,
+  #[account(
+    mut,
+    token::mint = mint,
+    token::token_program = token_program,
+  )]
+  pub receiver_token_account: InterfaceAccount<'info, TokenAccount>,
+  #[account(
+    seeds = [CONFIG_SEED],
+    bump = config.bump,
+  )]
+  pub config: Account<'info, ProtocolConfig>,
+  /// CHECK: Program implementing `on_flash_loan`, chosen by the borrower
+  #[account(executable)]
+  pub receiver_program: UncheckedAccount<'info>,
+  pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeSolPool<'info> {
+  #[account(mut)]
+  pub admin: Signer<'info>,
+  #[account(
+    seeds = [CONFIG_SEED],
+    bump = config.bump,
+    has_one = admin @ ProtocolError::Unauthorized,
+  )]
+  pub config: Account<'info, ProtocolConfig>,
+  #[account(
+    init,
+    payer = admin,
+    space = 8 + Pool::INIT_SPACE,
+    seeds = [POOL_SEED, SOL_POOL_MINT.as_ref()],
+    bump,
+  )]
+  pub pool: Account<'info, Pool>,
+  #[account(
+    init,
+    payer = admin,
+    space = 8 + SolVault::INIT_SPACE,
+    seeds = [SOL_VAULT_SEED, pool.key().as_ref()],
+    bump,
+  )]
+  pub vault: Account<'info, SolVault>,
+  #[account(
+    init,
+    payer = admin,
+    seeds = [LP_MINT_SEED, pool.key().as_ref()],
+    bump,
+    mint::decimals = 9,
+    mint::authority = pool,
+    mint::token_program = token_program,
+  )]
+  pub lp_mint: InterfaceAccount<'info, Mint>,
+  pub token_program: Interface<'info, TokenInterface>,
+  pub system_program: Program<'info, System>
+}
+
+#[derive(Accounts)]
+pub struct SolLiquidity<'info> {
+  #[account(mut)]
+  pub provider: Signer<'info>,
+  #[account(
+    seeds = [POOL_SEED, SOL_POOL_MINT.as_ref()],
+    bump = pool.bump,
+    has_one = lp_mint,
+  )]
+  pub pool: Account<'info, Pool>,
+  #[account(
+    mut,
+    seeds = [SOL_VAULT_SEED, pool.key().as_ref()],
+    bump = vault.bump,
+  )]
+  pub vault: Account<'info, SolVault>,
+  #[account(mut)]
+  pub lp_mint: InterfaceAccount<'info, Mint>,
+  #[account(
+    init_if_needed,
+    payer = provider,
+    associated_token::mint = lp_mint,
+    associated_token::authority = provider,
+    associated_token::token_program = token_program,
+  )]
+  pub provider_lp_ata: InterfaceAccount<'info, TokenAccount>,
+  pub token_program: Interface<'info, TokenInterface>,
   pub associated_token_program: Program<'info, AssociatedToken>,
   pub system_program: Program<'info, System>
 }
@@ -161,12 +1444,60 @@ pub enum ProtocolError {
     InvalidProgram,
     #[msg("Invalid borrower ATA")]
     InvalidBorrowerAta,
-    #[msg("Invalid protocol ATA")]
-    InvalidProtocolAta,
+    #[msg("Invalid vault")]
+    InvalidVault,
     #[msg("Missing repay instruction")]
     MissingRepayIx,
     #[msg("Missing borrow instruction")]
     MissingBorrowIx,
     #[msg("Overflow")]
     Overflow,
+    #[msg("Invalid fee")]
+    InvalidFee,
+    #[msg("Unauthorized")]
+    Unauthorized,
+    #[msg("Borrow cap exceeded")]
+    BorrowCapExceeded,
+    #[msg("Loan in progress")]
+    LoanInProgress,
+    #[msg("Borrow accounts do not match repay accounts")]
+    BorrowAccountMismatch,
+    #[msg("Malformed instructions sysvar")]
+    MalformedInstructionsSysvar,
+    #[msg("Too many leading programs")]
+    TooManyLeadingPrograms,
+    #[msg("Protocol is paused")]
+    Paused,
+    #[msg("Invalid utilization cap")]
+    InvalidUtilization,
+    #[msg("Utilization cap exceeded")]
+    UtilizationCapExceeded,
+    #[msg("Mint extension not supported")]
+    UnsupportedMintExtension,
+    #[msg("Transfer fees are not supported by this pool")]
+    TransferFeeNotSupported,
+    #[msg("Invalid borrower")]
+    InvalidBorrower,
+    #[msg("Invalid flash loan receiver")]
+    InvalidReceiver,
+    #[msg("Too many CPI callers")]
+    TooManyCpiCallers,
+    #[msg("Program not allowed to borrow through CPI")]
+    UnauthorizedCaller,
+    #[msg("Only direct CPIs into the program are supported")]
+    CpiTooDeep,
+    #[msg("Loan must be repaid by the caller that opened it")]
+    CallerMismatch,
+    #[msg("Invalid protocol fee share")]
+    InvalidProtocolShare,
+    #[msg("Invalid treasury")]
+    InvalidTreasury,
+    #[msg("Repay does not run at the index recorded in the loan receipt")]
+    RepayIndexMismatch,
+    #[msg("First deposit below the pool minimum")]
+    DepositTooSmall,
+    #[msg("Loan receipt still open")]
+    LoanNotRepaid,
+    #[msg("CPI loan without a later check_repaid of its receipt")]
+    MissingRepayCheck,
 }
\ No newline at end of file
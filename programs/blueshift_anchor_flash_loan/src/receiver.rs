@@ -0,0 +1,66 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::{AccountMeta, Instruction};
+
+/// Anchor discriminator of `on_flash_loan` (`sha256("global:on_flash_loan")[..8]`)
+pub const ON_FLASH_LOAN_DISCRIMINATOR: [u8; 8] = [195, 212, 238, 236, 80, 204, 73, 167];
+
+/// Position of the initiator (signer of `flash_loan`) in the `on_flash_loan` accounts
+pub const INITIATOR_INDEX: usize = 0;
+/// Position of the lent mint in the `on_flash_loan` accounts
+pub const MINT_INDEX: usize = 1;
+/// Position of the pool vault, where the funds have to be returned, in the `on_flash_loan` accounts
+pub const RECEIVER_VAULT_INDEX: usize = 2;
+/// Position of the token account holding the lent funds in the `on_flash_loan` accounts
+pub const RECEIVER_TOKEN_ACCOUNT_INDEX: usize = 3;
+/// Position of the token program in the `on_flash_loan` accounts
+pub const TOKEN_PROGRAM_INDEX: usize = 4;
+/// Number of accounts passed to `on_flash_loan` before the `flash_loan` remaining accounts
+pub const ON_FLASH_LOAN_ACCOUNTS: usize = 5;
+
+/// Arguments of the `on_flash_loan` instruction a receiver program has to implement
+///
+/// Receivers are called with:
+///   0. initiator (signer, read-only)
+///   1. mint
+///   2. pool vault (writable)
+///   3. receiver token account holding the lent funds (writable)
+///   4. token program
+///   5. onwards, the remaining accounts of `flash_loan` in order
+///
+/// Before returning, the receiver has to transfer `amount + fee` back to the
+/// vault. It should also check the initiator, anyone can call `flash_loan`
+/// with any receiver.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct OnFlashLoan {
+  pub initiator: Pubkey,
+  pub mint: Pubkey,
+  pub amount: u64,
+  pub fee: u64,
+  /// Opaque data forwarded from `flash_loan`
+  pub data: Vec<u8>,
+}
+
+impl OnFlashLoan {
+  /// Instruction data: discriminator followed by the borsh encoded arguments
+  pub fn data(&self) -> Vec<u8> {
+    let mut data = ON_FLASH_LOAN_DISCRIMINATOR.to_vec();
+    // Writing into a Vec cannot fail
+    self.serialize(&mut data).unwrap();
+    data
+  }
+
+  /// Decode instruction data built by [`OnFlashLoan::data`]
+  pub fn try_from_data(data: &[u8]) -> Option<Self> {
+    let args = data.strip_prefix(&ON_FLASH_LOAN_DISCRIMINATOR)?;
+    Self::try_from_slice(args).ok()
+  }
+
+  /// `on_flash_loan` instruction of `receiver_program`, `accounts` being the fixed accounts followed by the remaining ones
+  pub fn instruction(&self, receiver_program: Pubkey, accounts: Vec<AccountMeta>) -> Instruction {
+    Instruction {
+      program_id: receiver_program,
+      accounts,
+      data: self.data(),
+    }
+  }
+}
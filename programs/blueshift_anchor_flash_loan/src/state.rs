@@ -0,0 +1,217 @@
+use anchor_lang::prelude::*;
+
+use crate::{fees::{Rounding, loan_fee, protocol_fee}, ProtocolError, BPS_DENOMINATOR};
+
+/// Seed of the global protocol configuration PDA
+pub const CONFIG_SEED: &[u8] = b"config";
+/// Seed of the per-mint pool PDA, followed by the mint address
+pub const POOL_SEED: &[u8] = b"pool";
+/// Seed of the pool LP share mint, followed by the pool address
+pub const LP_MINT_SEED: &[u8] = b"lp_mint";
+/// Seed of the transient loan receipt PDA, followed by the pool and borrower addresses
+pub const LOAN_SEED: &[u8] = b"loan";
+/// Seed of the vault holding the lamports of the SOL pool, followed by the pool address
+pub const SOL_VAULT_SEED: &[u8] = b"sol_vault";
+/// Stand-in mint of the SOL pool, seeded like token pools (`[POOL_SEED, SOL_POOL_MINT]`)
+///
+/// The system program id can never be a token mint, so no token pool collides with it.
+pub const SOL_POOL_MINT: Pubkey = anchor_lang::system_program::ID;
+/// Maximum number of programs allowed to run before a borrow
+pub const MAX_LEADING_PROGRAMS: usize = 8;
+/// Maximum number of programs allowed to borrow through a CPI
+pub const MAX_CPI_CALLERS: usize = 8;
+/// Shares and assets every pool counts on top of its real ones when pricing LP shares
+///
+/// A donation to the vault is shared with these virtual shares, so inflating the
+/// share price costs an attacker more than the rounding it can take from later deposits.
+pub const VIRTUAL_LIQUIDITY: u64 = 1_000;
+/// Smallest first deposit of a pool, in base units of its mint
+pub const MIN_FIRST_DEPOSIT: u64 = 1_000;
+
+#[account]
+#[derive(InitSpace)]
+pub struct ProtocolConfig {
+  /// Key allowed to update the protocol parameters
+  pub admin: Pubkey,
+  /// Key allowed to pause and unpause borrowing, besides the admin
+  pub guardian: Pubkey,
+  /// When set, no new loan can be opened
+  pub paused: bool,
+  /// Fee given to newly created pools, in basis points
+  pub fee_bps: u16,
+  /// Share of every loan fee set aside for the protocol, in basis points
+  pub protocol_share_bps: u16,
+  /// Owner of the token accounts collecting the protocol fees
+  pub treasury: Pubkey,
+  /// Programs whose instructions may be placed before a borrow
+  #[max_len(MAX_LEADING_PROGRAMS)]
+  pub allowed_leading_programs: Vec<Pubkey>,
+  /// Programs allowed to borrow and repay through a CPI, their loans are checked by `check_repaid`
+  #[max_len(MAX_CPI_CALLERS)]
+  pub allowed_cpi_callers: Vec<Pubkey>,
+  pub bump: u8,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct Pool {
+  /// Mint lent out by this pool
+  pub mint: Pubkey,
+  /// Token account holding the pool liquidity, owned by the pool PDA
+  pub vault: Pubkey,
+  /// Mint of the shares handed out to liquidity providers
+  pub lp_mint: Pubkey,
+  /// Fee charged on every loan, in basis points
+  pub fee_bps: u16,
+  /// Rounding of every loan fee
+  pub fee_rounding: Rounding,
+  /// Smallest fee charged on any loan, in base units of the lent mint (0 for none)
+  pub min_fee: u64,
+  /// Largest amount a single loan can take (0 means no cap)
+  pub max_borrow: u64,
+  /// Largest share of the pool liquidity that can be lent out at once, in basis points (0 means no cap)
+  pub max_utilization_bps: u16,
+  /// How loans of a mint charging Token-2022 transfer fees are handled
+  pub transfer_fee_mode: TransferFeeMode,
+  /// Principal currently lent out and not yet repaid
+  pub outstanding: u64,
+  /// Sum of every principal repaid to the pool
+  pub total_borrowed: u64,
+  /// Sum of every fee earned by the liquidity providers
+  pub lp_fees: u64,
+  /// Sum of every fee earned by the protocol
+  pub protocol_fees: u64,
+  /// Protocol fees held in the vault until `collect_protocol_fees`, not part of the LP assets
+  pub unclaimed_protocol_fees: u64,
+  /// Number of loans settled by the pool
+  pub loan_count: u64,
+  pub bump: u8,
+}
+
+/// Program-owned account holding the lamports of the SOL pool
+///
+/// Only the balance above its rent-exempt minimum can be lent out.
+#[account]
+#[derive(InitSpace)]
+pub struct SolVault {
+  pub bump: u8,
+}
+
+/// Handling of Token-2022 transfer fees, which make the vault receive less than what is sent to it
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq, InitSpace)]
+pub enum TransferFeeMode {
+  /// Borrowing is refused while the mint charges a transfer fee
+  Refuse,
+  /// Repays send enough for the vault to net the principal plus the loan fee
+  GrossUp,
+}
+
+/// Cost of a loan and liquidity of a pool, returned by `quote`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LoanQuote {
+  /// Fee charged on top of the principal
+  pub fee: u64,
+  /// Amount `repay` takes from the borrower, grossed up by the mint transfer fee if any
+  pub total: u64,
+  /// Largest loan the pool can pay out right now, within its caps
+  pub available: u64,
+}
+
+/// Receipt of an open loan, created by the borrow and closed by the repay settling it
+///
+/// Top-level borrows are paired with the repay closing the receipt at the
+/// recorded index. CPI borrows cannot see their repay, so they require a later
+/// top-level `check_repaid` of the receipt, which fails while it is open.
+#[account]
+#[derive(InitSpace)]
+pub struct LoanReceipt {
+  /// Vault balance right before the loan was paid out
+  pub pre_balance: u64,
+  /// Amount lent out by the borrow (0 while no loan is open)
+  pub principal: u64,
+  /// Fee owed on top of the principal, fixed at borrow time
+  pub fee: u64,
+  /// Program that borrowed through a CPI, `None` for top-level loans
+  pub caller: Option<Pubkey>,
+  /// Top-level instruction the repay has to run in, the one of the borrow itself for CPI loans
+  pub repay_index: u16,
+  pub bump: u8,
+}
+
+impl Pool {
+  /// Check a new loan of `amount` against the pool caps, `vault_balance` being the current vault balance
+  ///
+  /// The utilization cap covers every loan open on the pool, so it also bounds
+  /// what a single transaction can take through several loans.
+  pub fn check_borrow_caps(&self, amount: u64, vault_balance: u64) -> Result<()> {
+    require!(self.max_borrow == 0 || amount <= self.max_borrow, ProtocolError::BorrowCapExceeded);
+
+    if self.max_utilization_bps > 0 {
+      let liquidity = vault_balance as u128 + self.outstanding as u128;
+      let max_outstanding = liquidity * self.max_utilization_bps as u128 / BPS_DENOMINATOR;
+      let new_outstanding = self.outstanding as u128 + amount as u128;
+      require!(new_outstanding <= max_outstanding, ProtocolError::UtilizationCapExceeded);
+    }
+
+    Ok(())
+  }
+
+  /// Largest loan `check_borrow_caps` lets through, `vault_balance` being the current vault balance
+  pub fn max_loan(&self, vault_balance: u64) -> u64 {
+    let mut max = vault_balance;
+    if self.max_borrow > 0 {
+      max = max.min(self.max_borrow);
+    }
+
+    if self.max_utilization_bps > 0 {
+      let liquidity = vault_balance as u128 + self.outstanding as u128;
+      let max_outstanding = liquidity * self.max_utilization_bps as u128 / BPS_DENOMINATOR;
+      let headroom = max_outstanding.saturating_sub(self.outstanding as u128);
+      max = max.min(u64::try_from(headroom).unwrap_or(u64::MAX));
+    }
+
+    max
+  }
+
+  /// Fee charged on a loan of `amount`, following the pool rate, rounding and minimum fee
+  pub fn loan_fee(&self, amount: u64) -> Result<u64> {
+    loan_fee(amount, self.fee_bps, self.fee_rounding, self.min_fee)
+  }
+
+  /// Split a settled loan `fee` between the liquidity providers and the protocol
+  pub fn accrue_fee(&mut self, fee: u64, protocol_share_bps: u16) -> Result<()> {
+    let protocol = protocol_fee(fee, protocol_share_bps);
+
+    self.lp_fees = self.lp_fees.checked_add(fee - protocol).ok_or(ProtocolError::Overflow)?;
+    self.protocol_fees = self.protocol_fees.checked_add(protocol).ok_or(ProtocolError::Overflow)?;
+    self.unclaimed_protocol_fees = self.unclaimed_protocol_fees.checked_add(protocol).ok_or(ProtocolError::Overflow)?;
+
+    Ok(())
+  }
+
+  /// Part of `vault_balance` backing the LP shares, unclaimed protocol fees excluded
+  pub fn lp_assets(&self, vault_balance: u64) -> u64 {
+    vault_balance.saturating_sub(self.unclaimed_protocol_fees)
+  }
+
+  /// Shares minted for `amount` when the pool holds `total_assets` against `total_shares`
+  ///
+  /// Both sides count `VIRTUAL_LIQUIDITY` on top, so an empty pool prices shares 1:1.
+  pub fn shares_for_deposit(amount: u64, total_assets: u64, total_shares: u64) -> Option<u64> {
+    u64::try_from(
+      (amount as u128)
+        .checked_mul(total_shares as u128 + VIRTUAL_LIQUIDITY as u128)?
+        .checked_div(total_assets as u128 + VIRTUAL_LIQUIDITY as u128)?
+    ).ok()
+  }
+
+  /// Tokens paid out when burning `shares` from a pool holding `total_assets` against `total_shares`
+  ///
+  /// Never more than `total_assets`, the virtual liquidity cannot be withdrawn.
+  pub fn assets_for_shares(shares: u64, total_assets: u64, total_shares: u64) -> Option<u64> {
+    let assets = (shares as u128)
+      .checked_mul(total_assets as u128 + VIRTUAL_LIQUIDITY as u128)?
+      .checked_div(total_shares as u128 + VIRTUAL_LIQUIDITY as u128)?;
+    u64::try_from(assets.min(total_assets as u128)).ok()
+  }
+}
@@ -0,0 +1,106 @@
+// Helpers shared by the LiteSVM tests and the compute unit bench
+//
+// The bench includes this file with `#[path]`, so each target only uses part
+// of it.
+
+#![allow(dead_code)]
+
+use anchor_lang::prelude::Pubkey;
+use anchor_lang::solana_program::bpf_loader_upgradeable::{self, UpgradeableLoaderState};
+use anchor_lang::solana_program::instruction::Instruction;
+use anchor_spl::associated_token::get_associated_token_address_with_program_id;
+use blueshift_anchor_flash_loan::ID;
+use litesvm::LiteSVM;
+use solana_sdk::account::Account;
+use solana_sdk::instruction::{AccountMeta as SdkAccountMeta, Instruction as SdkInstruction};
+use solana_sdk::pubkey::Pubkey as SdkPubkey;
+use solana_sdk::signature::{Keypair, Signer};
+
+pub fn address(key: &Pubkey) -> SdkPubkey {
+    SdkPubkey::new_from_array(key.to_bytes())
+}
+
+pub fn key(signer: &Keypair) -> Pubkey {
+    Pubkey::new_from_array(signer.pubkey().to_bytes())
+}
+
+pub fn sdk_instruction(ix: Instruction) -> SdkInstruction {
+    SdkInstruction {
+        program_id: address(&ix.program_id),
+        accounts: ix
+            .accounts
+            .iter()
+            .map(|meta| SdkAccountMeta {
+                pubkey: address(&meta.pubkey),
+                is_signer: meta.is_signer,
+                is_writable: meta.is_writable,
+            })
+            .collect(),
+        data: ix.data,
+    }
+}
+
+pub fn ata(owner: &Pubkey, mint: &Pubkey, token_program: &Pubkey) -> Pubkey {
+    get_associated_token_address_with_program_id(owner, mint, token_program)
+}
+
+pub fn program_data_pda() -> Pubkey {
+    Pubkey::find_program_address(&[ID.as_ref()], &bpf_loader_upgradeable::ID).0
+}
+
+/// Load the program through the upgradeable loader, `upgrade_authority` being allowed to initialize the protocol
+pub fn add_upgradeable_program(svm: &mut LiteSVM, program: &[u8], upgrade_authority: &Pubkey) {
+    let program_data = program_data_pda();
+    let loader = address(&bpf_loader_upgradeable::ID);
+
+    // Bincode layouts of `UpgradeableLoaderState::ProgramData` (deployed at slot 0) and `UpgradeableLoaderState::Program`
+    let mut data = vec![3, 0, 0, 0];
+    data.extend([0; 8]);
+    data.push(1);
+    data.extend(upgrade_authority.to_bytes());
+    assert_eq!(data.len(), UpgradeableLoaderState::size_of_programdata_metadata());
+    data.extend(program);
+    let program_data_account = Account {
+        lamports: svm.minimum_balance_for_rent_exemption(data.len()),
+        data,
+        owner: loader,
+        executable: false,
+        rent_epoch: 0,
+    };
+    svm.set_account(address(&program_data), program_data_account).unwrap();
+
+    let mut data = vec![2, 0, 0, 0];
+    data.extend(program_data.to_bytes());
+    let program_account = Account {
+        lamports: svm.minimum_balance_for_rent_exemption(data.len()),
+        data,
+        owner: loader,
+        executable: true,
+        rent_epoch: 0,
+    };
+    svm.set_account(address(&ID), program_account).unwrap();
+}
+
+/// Compute units consumed by each top-level instruction of this program, from the transaction logs
+pub fn instruction_compute_units(logs: &[String]) -> Vec<u64> {
+    let program_id = ID.to_string();
+    let mut depth = 0;
+    let mut units = Vec::new();
+
+    for log in logs {
+        let Some(rest) = log.strip_prefix("Program ") else { continue };
+        let mut words = rest.split_whitespace();
+        let (Some(program), Some(action)) = (words.next(), words.next()) else { continue };
+
+        match action {
+            "invoke" => depth += 1,
+            "success" | "failed:" => depth -= 1,
+            "consumed" if depth == 1 && program == program_id => {
+                units.extend(words.next().and_then(|consumed| consumed.parse::<u64>().ok()));
+            }
+            _ => {}
+        }
+    }
+
+    units
+}
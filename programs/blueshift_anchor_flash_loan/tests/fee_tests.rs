@@ -0,0 +1,135 @@
+// Property tests of the fee math shared by the program and its clients
+
+#[cfg(test)]
+mod tests {
+    use blueshift_anchor_flash_loan::fees::{compute_fee, loan_fee, protocol_fee, total_due, Rounding};
+    use blueshift_anchor_flash_loan::{BPS_DENOMINATOR, MAX_FEE_BPS};
+    use proptest::prelude::*;
+
+    fn rounding() -> impl Strategy<Value = Rounding> {
+        prop_oneof![Just(Rounding::Down), Just(Rounding::Up)]
+    }
+
+    proptest! {
+        /// Configurable rates never overflow and never charge more than the principal
+        #[test]
+        fn fee_is_bounded(amount in any::<u64>(), fee_bps in 0..=MAX_FEE_BPS, rounding in rounding()) {
+            let fee = compute_fee(amount, fee_bps, rounding).unwrap();
+            prop_assert!(fee <= amount);
+
+            // The total only overflows when the principal plus fee does not fit in a u64
+            let total = total_due(amount, fee_bps, rounding, 0);
+            prop_assert_eq!(total.is_ok(), amount.checked_add(fee).is_some());
+            if let Ok(total) = total {
+                prop_assert_eq!(total, amount + fee);
+            }
+        }
+
+        /// Any rate fails cleanly instead of truncating once the fee leaves the u64 range
+        #[test]
+        fn fee_overflow_is_reported(amount in any::<u64>(), fee_bps in any::<u16>(), rounding in rounding()) {
+            let exact = amount as u128 * fee_bps as u128;
+            let expected = match rounding {
+                Rounding::Down => exact / BPS_DENOMINATOR,
+                Rounding::Up => exact.div_ceil(BPS_DENOMINATOR),
+            };
+
+            match compute_fee(amount, fee_bps, rounding) {
+                Ok(fee) => prop_assert_eq!(fee as u128, expected),
+                Err(_) => prop_assert!(expected > u64::MAX as u128),
+            }
+        }
+
+        /// Borrowing more or paying a higher rate never costs less
+        #[test]
+        fn fee_is_monotonic(
+            a in any::<u64>(),
+            b in any::<u64>(),
+            bps_a in 0..=MAX_FEE_BPS,
+            bps_b in 0..=MAX_FEE_BPS,
+            rounding in rounding(),
+        ) {
+            let (low, high) = (a.min(b), a.max(b));
+            prop_assert!(compute_fee(low, bps_a, rounding).unwrap() <= compute_fee(high, bps_a, rounding).unwrap());
+
+            let (low_bps, high_bps) = (bps_a.min(bps_b), bps_a.max(bps_b));
+            prop_assert!(compute_fee(a, low_bps, rounding).unwrap() <= compute_fee(a, high_bps, rounding).unwrap());
+        }
+
+        /// Rounding up adds at most one unit, and only when the exact fee is fractional
+        #[test]
+        fn rounding_brackets_exact_fee(amount in any::<u64>(), fee_bps in 0..=MAX_FEE_BPS) {
+            let down = compute_fee(amount, fee_bps, Rounding::Down).unwrap() as u128;
+            let up = compute_fee(amount, fee_bps, Rounding::Up).unwrap() as u128;
+            let exact = amount as u128 * fee_bps as u128;
+
+            prop_assert!(down * BPS_DENOMINATOR <= exact);
+            prop_assert!(exact <= up * BPS_DENOMINATOR);
+            prop_assert_eq!(up - down, u128::from(!exact.is_multiple_of(BPS_DENOMINATOR)));
+        }
+    }
+
+    proptest! {
+        /// Every loan pays at least the minimum fee, and never less than the rate alone
+        #[test]
+        fn min_fee_is_a_floor(
+            amount in any::<u64>(),
+            fee_bps in 0..=MAX_FEE_BPS,
+            rounding in rounding(),
+            min_fee in any::<u64>(),
+        ) {
+            let fee = loan_fee(amount, fee_bps, rounding, min_fee).unwrap();
+            prop_assert_eq!(fee, compute_fee(amount, fee_bps, rounding).unwrap().max(min_fee));
+
+            if let Ok(total) = total_due(amount, fee_bps, rounding, min_fee) {
+                prop_assert_eq!(total, amount + fee);
+            }
+        }
+
+        /// Rounding up, splitting a loan in two never lowers the total fee
+        #[test]
+        fn split_loans_pay_at_least_whole_fee(
+            first in 0..=u64::MAX / 2,
+            second in 0..=u64::MAX / 2,
+            fee_bps in 0..=MAX_FEE_BPS,
+            min_fee in 0..=1_000_000u64,
+        ) {
+            let whole = loan_fee(first + second, fee_bps, Rounding::Up, min_fee).unwrap();
+            let split = loan_fee(first, fee_bps, Rounding::Up, min_fee).unwrap()
+                + loan_fee(second, fee_bps, Rounding::Up, min_fee).unwrap();
+            prop_assert!(split >= whole);
+        }
+    }
+
+    proptest! {
+        /// The protocol never takes more than its share of a fee, and never more than the fee
+        #[test]
+        fn protocol_fee_is_bounded(fee in any::<u64>(), protocol_share_bps in any::<u16>()) {
+            let protocol = protocol_fee(fee, protocol_share_bps);
+            prop_assert!(protocol <= fee);
+            prop_assert!(protocol as u128 * BPS_DENOMINATOR <= fee as u128 * protocol_share_bps as u128);
+        }
+    }
+
+    /// Boundaries of the u64 range
+    #[test]
+    fn test_fee_boundaries() {
+        println!("🚀 Testing Fee Boundaries");
+
+        assert_eq!(compute_fee(0, MAX_FEE_BPS, Rounding::Up).unwrap(), 0);
+        assert_eq!(compute_fee(u64::MAX, 0, Rounding::Up).unwrap(), 0);
+        assert_eq!(compute_fee(u64::MAX, MAX_FEE_BPS, Rounding::Down).unwrap(), u64::MAX);
+        assert_eq!(compute_fee(u64::MAX, 500, Rounding::Down).unwrap(), u64::MAX / 20);
+        assert_eq!(compute_fee(u64::MAX, 500, Rounding::Up).unwrap(), u64::MAX / 20 + 1);
+        assert!(compute_fee(u64::MAX, u16::MAX, Rounding::Down).is_err());
+
+        // Totals past u64::MAX cannot be repaid
+        assert!(total_due(u64::MAX, 1, Rounding::Up, 0).is_err());
+        assert!(total_due(u64::MAX, 0, Rounding::Up, 1).is_err(), "The minimum fee counts towards the total");
+        assert_eq!(total_due(u64::MAX, 0, Rounding::Up, 0).unwrap(), u64::MAX, "Without a fee the whole range can be repaid");
+        assert_eq!(total_due(9_999, 1, Rounding::Down, 0).unwrap(), 9_999, "The fee rounds down to 0");
+        assert_eq!(total_due(9_999, 1, Rounding::Up, 0).unwrap(), 10_000);
+
+        println!("✅ Fee boundaries test passed");
+    }
+}
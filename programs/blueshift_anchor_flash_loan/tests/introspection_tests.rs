@@ -0,0 +1,593 @@
+// Introspection tests over hand-built instructions sysvar buffers
+
+#[cfg(test)]
+mod tests {
+    use anchor_lang::prelude::{AccountMeta, Pubkey};
+    use anchor_lang::solana_program::instruction::Instruction;
+    use anchor_lang::solana_program::sysvar::instructions::{
+        construct_instructions_data, BorrowedAccountMeta, BorrowedInstruction,
+    };
+    use anchor_lang::{error::Error, AnchorDeserialize, InstructionData};
+    use blueshift_anchor_flash_loan::introspection::{
+        account_key, check_leading_instructions, find_paired_borrow,
+        find_repay_check, invocation, is_borrow, is_repay, is_repay_check, pair_loans, validate_borrow,
+        InstructionsSysvar, Invocation, COMPUTE_BUDGET_PROGRAM_ID, MEMO_PROGRAM_ID, SOL_LOAN_INDEX, VAULT_INDEX,
+    };
+    use blueshift_anchor_flash_loan::{instruction, ProtocolError, ID};
+
+    /// Serialize instructions the same way the runtime fills the instructions sysvar
+    fn sysvar_bytes(ixs: &[Instruction], current_index: u16) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&(ixs.len() as u16).to_le_bytes());
+        data.resize(2 + ixs.len() * 2, 0);
+
+        for (i, ix) in ixs.iter().enumerate() {
+            let offset = data.len() as u16;
+            data[2 + i * 2..4 + i * 2].copy_from_slice(&offset.to_le_bytes());
+
+            data.extend_from_slice(&(ix.accounts.len() as u16).to_le_bytes());
+            for meta in &ix.accounts {
+                data.push(meta.is_signer as u8 | (meta.is_writable as u8) << 1);
+                data.extend_from_slice(meta.pubkey.as_ref());
+            }
+            data.extend_from_slice(ix.program_id.as_ref());
+            data.extend_from_slice(&(ix.data.len() as u16).to_le_bytes());
+            data.extend_from_slice(&ix.data);
+        }
+
+        data.extend_from_slice(&current_index.to_le_bytes());
+        data
+    }
+
+    fn loan_accounts(borrower_ata: Pubkey, vault: Pubkey) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new(Pubkey::new_unique(), true),
+            AccountMeta::new(Pubkey::new_unique(), false),
+            AccountMeta::new_readonly(Pubkey::new_unique(), false),
+            AccountMeta::new(borrower_ata, false),
+            AccountMeta::new(vault, false),
+        ]
+    }
+
+    fn borrow_ix(amount: u64, borrower_ata: Pubkey, vault: Pubkey) -> Instruction {
+        Instruction {
+            program_id: ID,
+            accounts: loan_accounts(borrower_ata, vault),
+            data: instruction::Borrow { borrow_amount: amount }.data(),
+        }
+    }
+
+    fn repay_ix(borrower_ata: Pubkey, vault: Pubkey) -> Instruction {
+        Instruction {
+            program_id: ID,
+            accounts: loan_accounts(borrower_ata, vault),
+            data: instruction::Repay {}.data(),
+        }
+    }
+
+    fn borrow_amount(ix: &Instruction) -> u64 {
+        instruction::Borrow::try_from_slice(&ix.data[8..]).unwrap().borrow_amount
+    }
+
+    fn sol_loan_accounts(loan: Pubkey, vault: Pubkey) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new(Pubkey::new_unique(), true),
+            AccountMeta::new(Pubkey::new_unique(), false),
+            AccountMeta::new_readonly(Pubkey::new_unique(), false),
+            AccountMeta::new(loan, false),
+            AccountMeta::new(vault, false),
+        ]
+    }
+
+    fn borrow_sol_ix(amount: u64, loan: Pubkey, vault: Pubkey) -> Instruction {
+        Instruction {
+            program_id: ID,
+            accounts: sol_loan_accounts(loan, vault),
+            data: instruction::BorrowSol { borrow_amount: amount }.data(),
+        }
+    }
+
+    fn repay_sol_ix(loan: Pubkey, vault: Pubkey) -> Instruction {
+        Instruction {
+            program_id: ID,
+            accounts: sol_loan_accounts(loan, vault),
+            data: instruction::RepaySol {}.data(),
+        }
+    }
+
+    fn other_ix() -> Instruction {
+        Instruction {
+            program_id: Pubkey::new_unique(),
+            accounts: vec![AccountMeta::new_readonly(Pubkey::new_unique(), false)],
+            data: vec![1, 2, 3],
+        }
+    }
+
+    fn program_ix(program_id: Pubkey) -> Instruction {
+        Instruction {
+            program_id,
+            accounts: vec![],
+            data: vec![2, 0, 0, 0],
+        }
+    }
+
+    fn err(error: ProtocolError) -> Error {
+        error.into()
+    }
+
+    /// Test that the hand-built buffers match the runtime layout
+    #[test]
+    fn test_sysvar_layout_matches_runtime() {
+        println!("🚀 Testing Sysvar Layout");
+
+        let (borrower_ata, vault) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let ixs = vec![borrow_ix(1_000, borrower_ata, vault), other_ix(), repay_ix(borrower_ata, vault)];
+
+        let borrowed_ixs: Vec<BorrowedInstruction> = ixs
+            .iter()
+            .map(|ix| BorrowedInstruction {
+                program_id: &ix.program_id,
+                accounts: ix
+                    .accounts
+                    .iter()
+                    .map(|meta| BorrowedAccountMeta {
+                        pubkey: &meta.pubkey,
+                        is_signer: meta.is_signer,
+                        is_writable: meta.is_writable,
+                    })
+                    .collect(),
+                data: &ix.data,
+            })
+            .collect();
+
+        // The runtime leaves the current index zeroed until execution starts
+        assert_eq!(sysvar_bytes(&ixs, 0), construct_instructions_data(&borrowed_ixs),
+            "Hand-built buffer should match the runtime serialization");
+
+        println!("✅ Sysvar layout test passed");
+    }
+
+    /// Test that well-formed buffers decode back into the original instructions
+    #[test]
+    fn test_load_instruction_roundtrip() {
+        println!("🚀 Testing Instruction Roundtrip");
+
+        let (borrower_ata, vault) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let ixs = vec![borrow_ix(42, borrower_ata, vault), other_ix(), repay_ix(borrower_ata, vault)];
+        let data = sysvar_bytes(&ixs, 2);
+
+        let sysvar = InstructionsSysvar::new(&data).unwrap();
+        assert_eq!(sysvar.len(), 3);
+        assert!(!sysvar.is_empty());
+        assert_eq!(sysvar.current_index(), 2);
+
+        for (i, ix) in ixs.iter().enumerate() {
+            assert_eq!(&sysvar.load_instruction(i).unwrap(), ix, "Instruction {} should roundtrip", i);
+        }
+
+        // Instructions past the end are rejected instead of panicking
+        assert_eq!(sysvar.load_instruction(3).unwrap_err(), err(ProtocolError::InvalidInstructionIndex));
+        assert_eq!(sysvar.load_instruction(usize::MAX).unwrap_err(), err(ProtocolError::InvalidInstructionIndex));
+
+        println!("✅ Instruction roundtrip test passed");
+    }
+
+    /// Test every malformed header
+    #[test]
+    fn test_malformed_header() {
+        println!("🚀 Testing Malformed Headers");
+
+        let malformed = ProtocolError::MalformedInstructionsSysvar;
+
+        // Empty and truncated length
+        assert_eq!(InstructionsSysvar::new(&[]).err().unwrap(), err(malformed));
+        assert_eq!(InstructionsSysvar::new(&[1]).err().unwrap(), err(malformed));
+
+        // Length without a current index
+        assert_eq!(InstructionsSysvar::new(&[0, 0]).err().unwrap(), err(malformed));
+
+        // No instructions means no valid current index
+        assert_eq!(InstructionsSysvar::new(&[0, 0, 0, 0]).err().unwrap(), err(malformed));
+
+        // Offsets table longer than the buffer
+        assert_eq!(InstructionsSysvar::new(&[3, 0, 0, 0, 0, 0]).err().unwrap(), err(malformed));
+        assert_eq!(InstructionsSysvar::new(&[0xff, 0xff, 0, 0]).err().unwrap(), err(malformed));
+
+        // Current index out of range
+        let mut data = sysvar_bytes(&[other_ix()], 0);
+        let len = data.len();
+        data[len - 2..].copy_from_slice(&1u16.to_le_bytes());
+        assert_eq!(InstructionsSysvar::new(&data).err().unwrap(), err(malformed));
+
+        println!("✅ Malformed header test passed");
+    }
+
+    /// Test every truncation point inside an instruction
+    #[test]
+    fn test_malformed_instruction_body() {
+        println!("🚀 Testing Malformed Instruction Bodies");
+
+        let ix = other_ix();
+        let data = sysvar_bytes(std::slice::from_ref(&ix), 0);
+        let body_start = 4;
+
+        // Drop bytes from the body while keeping the header and current index intact
+        for cut in body_start..data.len() - 2 {
+            let mut truncated = data[..cut].to_vec();
+            truncated.extend_from_slice(&0u16.to_le_bytes());
+
+            let sysvar = InstructionsSysvar::new(&truncated).unwrap();
+            assert_eq!(sysvar.load_instruction(0).unwrap_err(), err(ProtocolError::MalformedInstructionsSysvar),
+                "Truncation at byte {} should be rejected", cut);
+        }
+
+        // Offset pointing past the end of the buffer
+        let mut bad_offset = data.clone();
+        bad_offset[2..4].copy_from_slice(&u16::MAX.to_le_bytes());
+        let sysvar = InstructionsSysvar::new(&bad_offset).unwrap();
+        assert_eq!(sysvar.load_instruction(0).unwrap_err(), err(ProtocolError::MalformedInstructionsSysvar));
+
+        // Account count larger than the buffer
+        let mut bad_accounts = data.clone();
+        bad_accounts[body_start..body_start + 2].copy_from_slice(&u16::MAX.to_le_bytes());
+        let sysvar = InstructionsSysvar::new(&bad_accounts).unwrap();
+        assert_eq!(sysvar.load_instruction(0).unwrap_err(), err(ProtocolError::MalformedInstructionsSysvar));
+
+        // Data length larger than the buffer
+        let mut bad_data_len = data.clone();
+        let data_len_at = data.len() - 2 - ix.data.len() - 2;
+        bad_data_len[data_len_at..data_len_at + 2].copy_from_slice(&u16::MAX.to_le_bytes());
+        let sysvar = InstructionsSysvar::new(&bad_data_len).unwrap();
+        assert_eq!(sysvar.load_instruction(0).unwrap_err(), err(ProtocolError::MalformedInstructionsSysvar));
+
+        println!("✅ Malformed instruction body test passed");
+    }
+
+    /// Test finding the repay paired with the borrow being executed
+    #[test]
+    fn test_repay_lookup() {
+        println!("🚀 Testing Repay Lookup");
+
+        let (borrower_ata, vault) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let borrow = borrow_ix(1_000, borrower_ata, vault);
+        let repay = repay_ix(borrower_ata, vault);
+
+        // Repay right after the borrow
+        let data = sysvar_bytes(&[borrow.clone(), repay.clone()], 0);
+        let sysvar = InstructionsSysvar::new(&data).unwrap();
+        let pair = validate_borrow(&sysvar, &[]).unwrap();
+        assert_eq!(pair.repay_index, 1);
+        assert_eq!(account_key(&pair.repay, 3), Some(borrower_ata));
+        assert_eq!(account_key(&pair.repay, 4), Some(vault));
+        assert_eq!(account_key(&pair.repay, 5), None);
+
+        // Unrelated instructions are skipped
+        let data = sysvar_bytes(&[borrow.clone(), other_ix(), other_ix(), repay.clone()], 0);
+        let sysvar = InstructionsSysvar::new(&data).unwrap();
+        assert_eq!(validate_borrow(&sysvar, &[]).unwrap().repay_index, 3);
+
+        // A repay discriminator sent to another program is not a repay
+        let mut fake_repay = repay.clone();
+        fake_repay.program_id = Pubkey::new_unique();
+        assert!(!is_repay(&fake_repay));
+        let data = sysvar_bytes(&[borrow.clone(), fake_repay], 0);
+        let sysvar = InstructionsSysvar::new(&data).unwrap();
+        assert_eq!(validate_borrow(&sysvar, &[]).err().unwrap(), err(ProtocolError::MissingRepayIx));
+
+        // A repay on another vault does not settle the borrow
+        let data = sysvar_bytes(&[borrow.clone(), repay_ix(borrower_ata, Pubkey::new_unique())], 0);
+        let sysvar = InstructionsSysvar::new(&data).unwrap();
+        assert_eq!(validate_borrow(&sysvar, &[]).err().unwrap(), err(ProtocolError::MissingBorrowIx));
+
+        // Repays placed before the borrow do not count
+        let data = sysvar_bytes(&[repay.clone(), borrow.clone()], 1);
+        let sysvar = InstructionsSysvar::new(&data).unwrap();
+        assert_eq!(validate_borrow(&sysvar, &[]).err().unwrap(), err(ProtocolError::MissingBorrowIx));
+
+        // Borrow alone
+        let data = sysvar_bytes(&[borrow], 0);
+        let sysvar = InstructionsSysvar::new(&data).unwrap();
+        assert_eq!(validate_borrow(&sysvar, &[]).err().unwrap(), err(ProtocolError::MissingRepayIx));
+
+        println!("✅ Repay lookup test passed");
+    }
+
+    /// Test finding the borrow settled by a repay
+    #[test]
+    fn test_find_paired_borrow() {
+        println!("🚀 Testing Borrow Lookup");
+
+        let (borrower_ata, vault) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let borrow = borrow_ix(1_000, borrower_ata, vault);
+        let repay = repay_ix(borrower_ata, vault);
+        assert!(is_borrow(&borrow));
+        assert!(!is_borrow(&repay));
+
+        // Borrow placed after leading compute budget instructions
+        let ixs = vec![
+            program_ix(COMPUTE_BUDGET_PROGRAM_ID),
+            program_ix(COMPUTE_BUDGET_PROGRAM_ID),
+            borrow.clone(),
+            other_ix(),
+            repay.clone(),
+        ];
+        let data = sysvar_bytes(&ixs, 4);
+        let sysvar = InstructionsSysvar::new(&data).unwrap();
+        let pair = find_paired_borrow(&sysvar, 4).unwrap();
+        assert_eq!(pair.borrow_index, 2);
+        assert_eq!(borrow_amount(&pair.borrow), 1_000);
+
+        // Borrows placed after the repay do not count
+        let data = sysvar_bytes(&[repay.clone(), borrow.clone()], 0);
+        let sysvar = InstructionsSysvar::new(&data).unwrap();
+        assert_eq!(find_paired_borrow(&sysvar, 0).err().unwrap(), err(ProtocolError::MissingBorrowIx));
+
+        // A borrow discriminator sent to another program is not a borrow
+        let mut fake_borrow = borrow.clone();
+        fake_borrow.program_id = Pubkey::new_unique();
+        let data = sysvar_bytes(&[fake_borrow, repay.clone()], 1);
+        let sysvar = InstructionsSysvar::new(&data).unwrap();
+        assert_eq!(find_paired_borrow(&sysvar, 1).err().unwrap(), err(ProtocolError::MissingBorrowIx));
+
+        // Indexes that are not a repay have no borrow
+        let data = sysvar_bytes(&[borrow, repay], 1);
+        let sysvar = InstructionsSysvar::new(&data).unwrap();
+        assert_eq!(find_paired_borrow(&sysvar, 0).err().unwrap(), err(ProtocolError::MissingBorrowIx));
+        assert_eq!(find_paired_borrow(&sysvar, usize::MAX).err().unwrap(), err(ProtocolError::MissingBorrowIx));
+
+        println!("✅ Borrow lookup test passed");
+    }
+
+    /// Test several loans in a single transaction
+    #[test]
+    fn test_pair_multiple_loans() {
+        println!("🚀 Testing Multiple Loans");
+
+        let (usdc_ata, usdc_vault) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (sol_ata, sol_vault) = (Pubkey::new_unique(), Pubkey::new_unique());
+
+        // Nested loans on different pools
+        let ixs = vec![
+            borrow_ix(1_000, usdc_ata, usdc_vault),
+            borrow_ix(2_000, sol_ata, sol_vault),
+            other_ix(),
+            repay_ix(sol_ata, sol_vault),
+            repay_ix(usdc_ata, usdc_vault),
+        ];
+        let data = sysvar_bytes(&ixs, 0);
+        let sysvar = InstructionsSysvar::new(&data).unwrap();
+        let pairs = pair_loans(&sysvar).unwrap();
+        assert_eq!(pairs.len(), 2);
+        assert_eq!((pairs[0].borrow_index, pairs[0].repay_index), (1, 3), "SOL repay should settle the SOL borrow");
+        assert_eq!((pairs[1].borrow_index, pairs[1].repay_index), (0, 4), "USDC repay should settle the USDC borrow");
+        assert_eq!(borrow_amount(&find_paired_borrow(&sysvar, 3).unwrap().borrow), 2_000);
+        assert_eq!(borrow_amount(&find_paired_borrow(&sysvar, 4).unwrap().borrow), 1_000);
+
+        // Interleaved loans on different pools
+        let ixs = vec![
+            borrow_ix(1_000, usdc_ata, usdc_vault),
+            borrow_ix(2_000, sol_ata, sol_vault),
+            repay_ix(usdc_ata, usdc_vault),
+            repay_ix(sol_ata, sol_vault),
+        ];
+        let data = sysvar_bytes(&ixs, 0);
+        let sysvar = InstructionsSysvar::new(&data).unwrap();
+        assert_eq!(find_paired_borrow(&sysvar, 2).unwrap().borrow_index, 0);
+        assert_eq!(find_paired_borrow(&sysvar, 3).unwrap().borrow_index, 1);
+
+        // Nested loans on the same pool settle innermost first
+        let ixs = vec![
+            borrow_ix(1_000, usdc_ata, usdc_vault),
+            repay_ix(usdc_ata, usdc_vault),
+            borrow_ix(3_000, usdc_ata, usdc_vault),
+            borrow_ix(4_000, usdc_ata, usdc_vault),
+            repay_ix(usdc_ata, usdc_vault),
+            repay_ix(usdc_ata, usdc_vault),
+        ];
+        let data = sysvar_bytes(&ixs, 0);
+        let sysvar = InstructionsSysvar::new(&data).unwrap();
+        let pairs: Vec<(usize, usize)> = pair_loans(&sysvar).unwrap().iter().map(|p| (p.borrow_index, p.repay_index)).collect();
+        assert_eq!(pairs, vec![(0, 1), (3, 4), (2, 5)]);
+
+        // Every borrow needs its own repay
+        let ixs = vec![
+            borrow_ix(1_000, usdc_ata, usdc_vault),
+            borrow_ix(2_000, usdc_ata, usdc_vault),
+            repay_ix(usdc_ata, usdc_vault),
+        ];
+        let data = sysvar_bytes(&ixs, 0);
+        let sysvar = InstructionsSysvar::new(&data).unwrap();
+        assert_eq!(pair_loans(&sysvar).err().unwrap(), err(ProtocolError::MissingRepayIx));
+
+        // Every repay needs its own borrow
+        let ixs = vec![
+            borrow_ix(1_000, usdc_ata, usdc_vault),
+            repay_ix(usdc_ata, usdc_vault),
+            repay_ix(usdc_ata, usdc_vault),
+        ];
+        let data = sysvar_bytes(&ixs, 0);
+        let sysvar = InstructionsSysvar::new(&data).unwrap();
+        assert_eq!(pair_loans(&sysvar).err().unwrap(), err(ProtocolError::MissingBorrowIx));
+
+        println!("✅ Multiple loans test passed");
+    }
+
+    /// Test the leading instructions policy
+    #[test]
+    fn test_check_leading_instructions() {
+        println!("🚀 Testing Leading Instructions Policy");
+
+        let (borrower_ata, vault) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let borrow = borrow_ix(1_000, borrower_ata, vault);
+        let repay = repay_ix(borrower_ata, vault);
+        let allowed = [COMPUTE_BUDGET_PROGRAM_ID, MEMO_PROGRAM_ID];
+
+        // Borrow first is always fine
+        let data = sysvar_bytes(&[borrow.clone(), repay.clone()], 0);
+        let sysvar = InstructionsSysvar::new(&data).unwrap();
+        assert!(check_leading_instructions(&sysvar, 0, &[]).is_ok());
+
+        // Compute budget and memo instructions may come first
+        let ixs = vec![
+            program_ix(COMPUTE_BUDGET_PROGRAM_ID),
+            program_ix(MEMO_PROGRAM_ID),
+            borrow.clone(),
+            repay.clone(),
+        ];
+        let data = sysvar_bytes(&ixs, 2);
+        let sysvar = InstructionsSysvar::new(&data).unwrap();
+        assert!(check_leading_instructions(&sysvar, 2, &allowed).is_ok());
+        assert_eq!(check_leading_instructions(&sysvar, 2, &allowed[..1]).unwrap_err(), err(ProtocolError::InvalidIx),
+            "Programs missing from the allowlist should be rejected");
+
+        // Anything else may not
+        let ixs = vec![program_ix(COMPUTE_BUDGET_PROGRAM_ID), other_ix(), borrow, repay];
+        let data = sysvar_bytes(&ixs, 2);
+        let sysvar = InstructionsSysvar::new(&data).unwrap();
+        assert_eq!(check_leading_instructions(&sysvar, 2, &allowed).unwrap_err(), err(ProtocolError::InvalidIx));
+
+        println!("✅ Leading instructions policy test passed");
+    }
+
+    /// Test SOL loans, paired like token loans on their vault
+    #[test]
+    fn test_pair_sol_loans() {
+        println!("🚀 Testing SOL Loans Pairing");
+
+        let (loan, sol_vault) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (usdc_ata, usdc_vault) = (Pubkey::new_unique(), Pubkey::new_unique());
+
+        assert!(is_borrow(&borrow_sol_ix(1_000, loan, sol_vault)));
+        assert!(is_repay(&repay_sol_ix(loan, sol_vault)));
+        assert!(!is_borrow(&repay_sol_ix(loan, sol_vault)));
+        assert!(!is_repay(&borrow_sol_ix(1_000, loan, sol_vault)));
+
+        // A SOL loan nested in a token loan
+        let ixs = vec![
+            borrow_ix(1_000, usdc_ata, usdc_vault),
+            borrow_sol_ix(5_000, loan, sol_vault),
+            other_ix(),
+            repay_sol_ix(loan, sol_vault),
+            repay_ix(usdc_ata, usdc_vault),
+        ];
+        let data = sysvar_bytes(&ixs, 1);
+        let sysvar = InstructionsSysvar::new(&data).unwrap();
+        let pairs: Vec<(usize, usize)> = pair_loans(&sysvar).unwrap().iter().map(|p| (p.borrow_index, p.repay_index)).collect();
+        assert_eq!(pairs, vec![(1, 3), (0, 4)]);
+
+        let pair = find_paired_borrow(&sysvar, 3).unwrap();
+        assert_eq!(account_key(&pair.borrow, SOL_LOAN_INDEX), Some(loan));
+        assert_eq!(account_key(&pair.borrow, VAULT_INDEX), Some(sol_vault));
+
+        // A SOL repay cannot settle a token borrow
+        let ixs = vec![borrow_ix(1_000, usdc_ata, usdc_vault), repay_sol_ix(loan, sol_vault)];
+        let data = sysvar_bytes(&ixs, 0);
+        let sysvar = InstructionsSysvar::new(&data).unwrap();
+        assert_eq!(pair_loans(&sysvar).err().unwrap(), err(ProtocolError::MissingBorrowIx));
+
+        println!("✅ SOL loans pairing test passed");
+    }
+
+    /// Test the checks run by the executing borrow
+    #[test]
+    fn test_validate_borrow() {
+        println!("🚀 Testing Borrow Validation");
+
+        let (borrower_ata, vault) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let allowed = [COMPUTE_BUDGET_PROGRAM_ID];
+        let ixs = vec![
+            program_ix(COMPUTE_BUDGET_PROGRAM_ID),
+            borrow_ix(1_000, borrower_ata, vault),
+            other_ix(),
+            repay_ix(borrower_ata, vault),
+        ];
+
+        // The executing borrow gets its repay
+        let data = sysvar_bytes(&ixs, 1);
+        let sysvar = InstructionsSysvar::new(&data).unwrap();
+        let pair = validate_borrow(&sysvar, &allowed).unwrap();
+        assert_eq!((pair.borrow_index, pair.repay_index), (1, 3));
+
+        // Leading programs must be allowed
+        assert_eq!(validate_borrow(&sysvar, &[]).err().unwrap(), err(ProtocolError::InvalidIx));
+
+        // The executing instruction must be a borrow of the transaction
+        let data = sysvar_bytes(&ixs, 2);
+        let sysvar = InstructionsSysvar::new(&data).unwrap();
+        assert_eq!(validate_borrow(&sysvar, &allowed).err().unwrap(), err(ProtocolError::MissingRepayIx));
+
+        println!("✅ Borrow validation test passed");
+    }
+
+    /// Test how loan instructions tell top-level calls from CPIs
+    #[test]
+    fn test_invocation() {
+        println!("🚀 Testing Invocation");
+
+        let integrator = Pubkey::new_unique();
+        let (borrower_ata, vault) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let ixs = vec![
+            program_ix(COMPUTE_BUDGET_PROGRAM_ID),
+            program_ix(integrator),
+            borrow_ix(1_000, borrower_ata, vault),
+            repay_ix(borrower_ata, vault),
+        ];
+
+        // At the top level the current index is the loan instruction itself
+        let data = sysvar_bytes(&ixs, 2);
+        let sysvar = InstructionsSysvar::new(&data).unwrap();
+        assert_eq!(invocation(&sysvar, 1).unwrap(), Invocation::TopLevel);
+
+        // Under a CPI it is the top-level instruction of the calling program
+        let data = sysvar_bytes(&ixs, 1);
+        let sysvar = InstructionsSysvar::new(&data).unwrap();
+        assert_eq!(invocation(&sysvar, 2).unwrap(), Invocation::Cpi { caller: integrator, index: 1 });
+
+        // Nested CPIs hide the program calling this one
+        assert_eq!(invocation(&sysvar, 3).err().unwrap(), err(ProtocolError::CpiTooDeep));
+
+        println!("✅ Invocation test passed");
+    }
+
+    /// Test that CPI loans look for a later `check_repaid` of their receipt
+    #[test]
+    fn test_find_repay_check() {
+        println!("🚀 Testing Repay Check Lookup");
+
+        let integrator = Pubkey::new_unique();
+        let (receipt, other_receipt) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let check_ix = |receipts: &[Pubkey]| Instruction {
+            program_id: ID,
+            accounts: receipts.iter().map(|receipt| AccountMeta::new_readonly(*receipt, false)).collect(),
+            data: instruction::CheckRepaid {}.data(),
+        };
+
+        let ixs = vec![
+            check_ix(&[receipt]),
+            program_ix(integrator),
+            check_ix(&[other_receipt]),
+            other_ix(),
+            check_ix(&[other_receipt, receipt]),
+        ];
+        assert!(is_repay_check(&ixs[0]));
+        assert!(!is_repay_check(&ixs[1]));
+        assert!(!is_borrow(&ixs[0]) && !is_repay(&ixs[0]), "The check is not a loan instruction");
+
+        // Only checks after the caller instruction and listing the receipt count
+        let data = sysvar_bytes(&ixs, 1);
+        let sysvar = InstructionsSysvar::new(&data).unwrap();
+        assert_eq!(find_repay_check(&sysvar, 1, &receipt).unwrap(), 4);
+        assert_eq!(find_repay_check(&sysvar, 1, &other_receipt).unwrap(), 2);
+        assert_eq!(find_repay_check(&sysvar, 4, &receipt).err().unwrap(), err(ProtocolError::MissingRepayCheck));
+        assert_eq!(find_repay_check(&sysvar, 1, &Pubkey::new_unique()).err().unwrap(), err(ProtocolError::MissingRepayCheck));
+
+        // The same data from another program is not a check
+        let mut fake_check = check_ix(&[receipt]);
+        fake_check.program_id = integrator;
+        let data = sysvar_bytes(&[program_ix(integrator), fake_check], 0);
+        let sysvar = InstructionsSysvar::new(&data).unwrap();
+        assert_eq!(find_repay_check(&sysvar, 0, &receipt).err().unwrap(), err(ProtocolError::MissingRepayCheck));
+
+        println!("✅ Repay check lookup test passed");
+    }
+}
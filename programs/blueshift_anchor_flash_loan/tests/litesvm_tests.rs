@@ -0,0 +1,1727 @@
+// End-to-end tests running the compiled program in LiteSVM
+//
+// Build the programs first (`anchor build` or `cargo build-sbf`), the tests skip
+// when `target/deploy/blueshift_anchor_flash_loan.so` is missing, and the
+// callback and CPI tests when the `flash_loan_receiver` or `cpi_borrower`
+// fixture is.
+//
+// LiteSVM and solana-sdk 3 use their own `Pubkey` and `Instruction` types, so
+// everything built with the program (Solana 2) types is converted at the edge.
+
+#![allow(deprecated)]
+
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{add_upgradeable_program, address, ata, key, program_data_pda, sdk_instruction};
+    use anchor_lang::prelude::{AccountMeta, Pubkey};
+    use anchor_lang::solana_program::instruction::Instruction;
+    use anchor_lang::solana_program::sysvar::instructions::ID as INSTRUCTIONS_SYSVAR_ID;
+    use anchor_lang::{AccountDeserialize, AnchorDeserialize, InstructionData, Space, ToAccountMetas};
+    use anchor_spl::associated_token::spl_associated_token_account::instruction::create_associated_token_account;
+    use anchor_spl::token_2022::spl_token_2022::{
+        self,
+        extension::{
+            permanent_delegate::PermanentDelegate, transfer_fee::TransferFeeConfig, BaseStateWithExtensionsMut,
+            ExtensionType, StateWithExtensions, StateWithExtensionsMut,
+        },
+    };
+    use blueshift_anchor_flash_loan::extensions::{amount_received, amount_to_send, mint_transfer_fee};
+    use blueshift_anchor_flash_loan::fees::Rounding;
+    use blueshift_anchor_flash_loan::{
+        accounts, instruction, LoanQuote, LoanReceipt, Pool, ProtocolConfig, ProtocolError, TransferFeeMode, CONFIG_SEED, ID,
+        LOAN_SEED, LP_MINT_SEED, MAX_FEE_BPS, MIN_FIRST_DEPOSIT, POOL_SEED, SOL_POOL_MINT, SOL_VAULT_SEED,
+    };
+    use litesvm::types::TransactionResult;
+    use litesvm::LiteSVM;
+    use solana_sdk::account::Account;
+    use solana_sdk::instruction::{Instruction as SdkInstruction, InstructionError};
+    use solana_sdk::signature::{Keypair, Signer};
+    use solana_sdk::transaction::{Transaction, TransactionError};
+    use spl_token::solana_program::program_option::COption;
+    use spl_token::solana_program::program_pack::Pack;
+
+    const PROGRAM_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../../target/deploy/blueshift_anchor_flash_loan.so");
+    const RECEIVER_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../../target/deploy/flash_loan_receiver.so");
+    const CPI_BORROWER_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../../target/deploy/cpi_borrower.so");
+    const FEE_BPS: u16 = 500;
+    const DECIMALS: u8 = 6;
+    const LIQUIDITY: u64 = 1_000_000_000;
+    const BORROWER_FUNDS: u64 = 10_000_000;
+
+    struct Env {
+        svm: LiteSVM,
+        admin: Keypair,
+        borrower: Keypair,
+        mint: Pubkey,
+        token_program: Pubkey,
+        pool: Pubkey,
+        vault: Pubkey,
+    }
+
+    struct SolEnv {
+        svm: LiteSVM,
+        admin: Keypair,
+        borrower: Keypair,
+        pool: Pubkey,
+        vault: Pubkey,
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn send(svm: &mut LiteSVM, ixs: Vec<Instruction>, payer: &Keypair, signers: &[&Keypair]) -> TransactionResult {
+        let ixs: Vec<SdkInstruction> = ixs.into_iter().map(sdk_instruction).collect();
+        let tx = Transaction::new_signed_with_payer(&ixs, Some(&payer.pubkey()), signers, svm.latest_blockhash());
+        svm.send_transaction(tx)
+    }
+
+    /// Store raw account data owned by `token_program`
+    fn set_token_data(svm: &mut LiteSVM, key: &Pubkey, token_program: &Pubkey, data: Vec<u8>) {
+        let account = Account {
+            lamports: svm.minimum_balance_for_rent_exemption(data.len()),
+            data,
+            owner: address(token_program),
+            executable: false,
+            rent_epoch: 0,
+        };
+        svm.set_account(address(key), account).unwrap();
+    }
+
+    /// Store a token account (or mint) without extensions, laid out the same by SPL Token and Token-2022
+    fn set_token_state<T: Pack>(svm: &mut LiteSVM, key: &Pubkey, token_program: &Pubkey, state: T) {
+        let mut data = vec![0; T::LEN];
+        T::pack(state, &mut data).unwrap();
+        set_token_data(svm, key, token_program, data);
+    }
+
+    fn set_token_account(svm: &mut LiteSVM, key: &Pubkey, token_program: &Pubkey, mint: &Pubkey, owner: &Pubkey, amount: u64) {
+        set_token_state(
+            svm,
+            key,
+            token_program,
+            spl_token::state::Account {
+                mint: *mint,
+                owner: *owner,
+                amount,
+                delegate: COption::None,
+                state: spl_token::state::AccountState::Initialized,
+                is_native: COption::None,
+                delegated_amount: 0,
+                close_authority: COption::None,
+            },
+        );
+    }
+
+    fn token_balance(svm: &LiteSVM, key: &Pubkey) -> u64 {
+        let account = svm.get_account(&address(key)).expect("token account should exist");
+        // Token-2022 vaults carry extensions after the base account
+        StateWithExtensions::<spl_token_2022::state::Account>::unpack(&account.data).unwrap().base.amount
+    }
+
+    /// Token-2022 mint data, charging a transfer fee of `transfer_fee_bps` if set
+    ///
+    /// Without extensions the layout is the one of an SPL Token mint.
+    fn mint_data(mint_authority: &Pubkey, transfer_fee_bps: Option<u16>) -> Vec<u8> {
+        let extensions: &[ExtensionType] = if transfer_fee_bps.is_some() { &[ExtensionType::TransferFeeConfig] } else { &[] };
+        let len = ExtensionType::try_calculate_account_len::<spl_token_2022::state::Mint>(extensions).unwrap();
+        let mut data = vec![0; len];
+        let mut state = StateWithExtensionsMut::<spl_token_2022::state::Mint>::unpack_uninitialized(&mut data).unwrap();
+        if let Some(bps) = transfer_fee_bps {
+            let config = state.init_extension::<TransferFeeConfig>(true).unwrap();
+            config.older_transfer_fee.transfer_fee_basis_points = bps.into();
+            config.older_transfer_fee.maximum_fee = u64::MAX.into();
+            config.newer_transfer_fee = config.older_transfer_fee;
+        }
+        state.base = spl_token_2022::state::Mint {
+            mint_authority: COption::Some(*mint_authority),
+            decimals: DECIMALS,
+            is_initialized: true,
+            ..Default::default()
+        };
+        state.pack_base();
+        state.init_account_type().unwrap();
+        data
+    }
+
+    /// Transfer fee currently charged by the mint of `env`
+    fn transfer_fee(env: &Env) -> Option<spl_token_2022::extension::transfer_fee::TransferFee> {
+        let account = env.svm.get_account(&address(&env.mint)).unwrap();
+        mint_transfer_fee(&env.token_program, &account.data, env.svm.get_sysvar::<solana_sdk::clock::Clock>().epoch).unwrap()
+    }
+
+    fn borrow_accounts(borrower: &Pubkey, mint: &Pubkey, token_program: &Pubkey) -> Vec<AccountMeta> {
+        let pool = Pubkey::find_program_address(&[POOL_SEED, mint.as_ref()], &ID).0;
+
+        accounts::Borrow {
+            borrower: *borrower,
+            pool,
+            mint: *mint,
+            borrower_ata: ata(borrower, mint, token_program),
+            vault: ata(&pool, mint, token_program),
+            config: Pubkey::find_program_address(&[CONFIG_SEED], &ID).0,
+            loan: Pubkey::find_program_address(&[LOAN_SEED, pool.as_ref(), borrower.as_ref()], &ID).0,
+            instructions: INSTRUCTIONS_SYSVAR_ID,
+            token_program: *token_program,
+            associated_token_program: anchor_spl::associated_token::ID,
+            system_program: anchor_lang::system_program::ID,
+        }
+        .to_account_metas(None)
+    }
+
+    fn repay_accounts(borrower: &Pubkey, mint: &Pubkey, token_program: &Pubkey) -> Vec<AccountMeta> {
+        let pool = Pubkey::find_program_address(&[POOL_SEED, mint.as_ref()], &ID).0;
+
+        accounts::Repay {
+            borrower: *borrower,
+            pool,
+            mint: *mint,
+            borrower_ata: ata(borrower, mint, token_program),
+            vault: ata(&pool, mint, token_program),
+            config: Pubkey::find_program_address(&[CONFIG_SEED], &ID).0,
+            loan: Pubkey::find_program_address(&[LOAN_SEED, pool.as_ref(), borrower.as_ref()], &ID).0,
+            instructions: INSTRUCTIONS_SYSVAR_ID,
+            token_program: *token_program,
+        }
+        .to_account_metas(None)
+    }
+
+    fn borrow_ix(env: &Env, amount: u64) -> Instruction {
+        borrow_ix_by(env, &key(&env.borrower), amount)
+    }
+
+    fn repay_ix(env: &Env) -> Instruction {
+        repay_ix_by(env, &key(&env.borrower))
+    }
+
+    /// `borrow` of `amount` on the pool of `env` by another `borrower`
+    fn borrow_ix_by(env: &Env, borrower: &Pubkey, amount: u64) -> Instruction {
+        Instruction {
+            program_id: ID,
+            accounts: borrow_accounts(borrower, &env.mint, &env.token_program),
+            data: instruction::Borrow { borrow_amount: amount }.data(),
+        }
+    }
+
+    fn repay_ix_by(env: &Env, borrower: &Pubkey) -> Instruction {
+        Instruction {
+            program_id: ID,
+            accounts: repay_accounts(borrower, &env.mint, &env.token_program),
+            data: instruction::Repay {}.data(),
+        }
+    }
+
+    /// New borrower holding `BORROWER_FUNDS` of the mint of `env`
+    fn add_borrower(env: &mut Env) -> Keypair {
+        let borrower = Keypair::new();
+        env.svm.airdrop(&borrower.pubkey(), 10_000_000_000).unwrap();
+
+        let admin = env.admin.insecure_clone();
+        let borrower_ata = ata(&key(&borrower), &env.mint, &env.token_program);
+        let funding = vec![
+            create_associated_token_account(&key(&admin), &key(&borrower), &env.mint, &env.token_program),
+            spl_token_2022::instruction::mint_to(&env.token_program, &env.mint, &borrower_ata, &key(&admin), &[], BORROWER_FUNDS)
+                .unwrap(),
+        ];
+        send(&mut env.svm, funding, &admin, &[&admin]).expect("borrower funding should succeed");
+
+        borrower
+    }
+
+    fn flash_loan_ix(env: &Env, receiver_program: &Pubkey, amount: u64) -> Instruction {
+        let borrower = key(&env.borrower);
+
+        Instruction {
+            program_id: ID,
+            accounts: accounts::FlashLoan {
+                borrower,
+                pool: env.pool,
+                mint: env.mint,
+                vault: env.vault,
+                receiver_token_account: ata(&borrower, &env.mint, &env.token_program),
+                config: Pubkey::find_program_address(&[CONFIG_SEED], &ID).0,
+                receiver_program: *receiver_program,
+                token_program: env.token_program,
+            }
+            .to_account_metas(None),
+            data: instruction::FlashLoan { amount, data: vec![] }.data(),
+        }
+    }
+
+    /// Load the `flash_loan_receiver` fixture and fund its token account with `funds`
+    ///
+    /// Returns the receiver token account, or `None` when the fixture has not been built.
+    fn add_receiver(env: &mut Env, funds: u64) -> Option<Pubkey> {
+        let Ok(program) = std::fs::read(RECEIVER_PATH) else {
+            println!("⚠️  Skipping: {} not found, build the programs first", RECEIVER_PATH);
+            return None;
+        };
+        env.svm.add_program(address(&flash_loan_receiver::ID), &program).unwrap();
+
+        let authority = receiver_authority();
+        let receiver_ata = ata(&authority, &env.mint, &env.token_program);
+        let admin = env.admin.insecure_clone();
+        let funding = vec![
+            create_associated_token_account(&key(&admin), &authority, &env.mint, &env.token_program),
+            spl_token_2022::instruction::mint_to(&env.token_program, &env.mint, &receiver_ata, &key(&admin), &[], funds).unwrap(),
+        ];
+        send(&mut env.svm, funding, &admin, &[&admin]).expect("receiver funding should succeed");
+
+        Some(receiver_ata)
+    }
+
+    fn receiver_authority() -> Pubkey {
+        Pubkey::find_program_address(&[flash_loan_receiver::AUTHORITY_SEED], &flash_loan_receiver::ID).0
+    }
+
+    /// `flash_loan` of `amount` calling the receiver fixture, which sends back the `u64` in `data` if any
+    fn receiver_flash_loan_ix(env: &Env, amount: u64, data: Vec<u8>) -> Instruction {
+        let authority = receiver_authority();
+        let mut accounts = accounts::FlashLoan {
+            borrower: key(&env.borrower),
+            pool: env.pool,
+            mint: env.mint,
+            vault: env.vault,
+            receiver_token_account: ata(&authority, &env.mint, &env.token_program),
+            config: Pubkey::find_program_address(&[CONFIG_SEED], &ID).0,
+            receiver_program: flash_loan_receiver::ID,
+            token_program: env.token_program,
+        }
+        .to_account_metas(None);
+        accounts.push(AccountMeta::new_readonly(authority, false));
+
+        Instruction {
+            program_id: ID,
+            accounts,
+            data: instruction::FlashLoan { amount, data }.data(),
+        }
+    }
+
+    /// Load the `cpi_borrower` fixture, allowing it to borrow through CPIs if `allowed`
+    fn add_cpi_borrower(env: &mut Env, allowed: bool) -> Option<()> {
+        let Ok(program) = std::fs::read(CPI_BORROWER_PATH) else {
+            println!("⚠️  Skipping: {} not found, build the programs first", CPI_BORROWER_PATH);
+            return None;
+        };
+        env.svm.add_program(address(&cpi_borrower::ID), &program).unwrap();
+
+        if allowed {
+            let admin = env.admin.insecure_clone();
+            let set_cpi_callers = admin_ix(&key(&admin), instruction::SetCpiCallers { programs: vec![cpi_borrower::ID] }.data());
+            send(&mut env.svm, vec![set_cpi_callers], &admin, &[&admin]).expect("CPI callers update should succeed");
+        }
+
+        Some(())
+    }
+
+    fn loan_pda(env: &Env) -> Pubkey {
+        Pubkey::find_program_address(&[LOAN_SEED, env.pool.as_ref(), key(&env.borrower).as_ref()], &ID).0
+    }
+
+    /// Accounts of a loan of `borrower` through the CPI borrower fixture
+    fn cpi_loan_accounts(env: &Env, borrower: &Pubkey) -> cpi_borrower::accounts::LoanAccounts {
+        cpi_borrower::accounts::LoanAccounts {
+            pool: env.pool,
+            mint: env.mint,
+            borrower_ata: ata(borrower, &env.mint, &env.token_program),
+            vault: env.vault,
+            config: Pubkey::find_program_address(&[CONFIG_SEED], &ID).0,
+            loan: Pubkey::find_program_address(&[LOAN_SEED, env.pool.as_ref(), borrower.as_ref()], &ID).0,
+            instructions: INSTRUCTIONS_SYSVAR_ID,
+            token_program: env.token_program,
+            associated_token_program: anchor_spl::associated_token::ID,
+            system_program: anchor_lang::system_program::ID,
+            flash_loan_program: ID,
+        }
+    }
+
+    /// `borrow_and_repay` of the CPI borrower fixture, which only borrows unless `repay`
+    fn cpi_borrow_ix(env: &Env, amount: u64, repay: bool) -> Instruction {
+        let borrower = key(&env.borrower);
+
+        Instruction {
+            program_id: cpi_borrower::ID,
+            accounts: cpi_borrower::accounts::BorrowAndRepay { borrower, loan_accounts: cpi_loan_accounts(env, &borrower) }
+                .to_account_metas(None),
+            data: cpi_borrower::instruction::BorrowAndRepay { amount, repay }.data(),
+        }
+    }
+
+    /// `repay` of the CPI borrower fixture, settling the loan of the borrower of `env` through a CPI
+    fn cpi_repay_ix(env: &Env) -> Instruction {
+        let borrower = key(&env.borrower);
+
+        Instruction {
+            program_id: cpi_borrower::ID,
+            accounts: cpi_borrower::accounts::BorrowAndRepay { borrower, loan_accounts: cpi_loan_accounts(env, &borrower) }
+                .to_account_metas(None),
+            data: cpi_borrower::instruction::Repay {}.data(),
+        }
+    }
+
+    fn cpi_borrower_pda() -> Pubkey {
+        Pubkey::find_program_address(&[cpi_borrower::BORROWER_SEED], &cpi_borrower::ID).0
+    }
+
+    /// `pda_borrow_and_repay` of the CPI borrower fixture, its PDA borrowing
+    fn cpi_pda_borrow_ix(env: &Env, amount: u64, repay: bool) -> Instruction {
+        let borrower = cpi_borrower_pda();
+
+        Instruction {
+            program_id: cpi_borrower::ID,
+            accounts: cpi_borrower::accounts::PdaBorrowAndRepay { borrower, loan_accounts: cpi_loan_accounts(env, &borrower) }
+                .to_account_metas(None),
+            data: cpi_borrower::instruction::PdaBorrowAndRepay { amount, repay }.data(),
+        }
+    }
+
+    fn check_repaid_ix(receipts: &[Pubkey]) -> Instruction {
+        let mut accounts = accounts::CheckRepaid { instructions: INSTRUCTIONS_SYSVAR_ID }.to_account_metas(None);
+        accounts.extend(receipts.iter().map(|receipt| AccountMeta::new_readonly(*receipt, false)));
+
+        Instruction {
+            program_id: ID,
+            accounts,
+            data: instruction::CheckRepaid {}.data(),
+        }
+    }
+
+    fn initialize_pool_ix(admin: &Pubkey, mint: &Pubkey, token_program: &Pubkey) -> Instruction {
+        let pool = Pubkey::find_program_address(&[POOL_SEED, mint.as_ref()], &ID).0;
+
+        Instruction {
+            program_id: ID,
+            accounts: accounts::InitializePool {
+                admin: *admin,
+                config: Pubkey::find_program_address(&[CONFIG_SEED], &ID).0,
+                mint: *mint,
+                pool,
+                vault: ata(&pool, mint, token_program),
+                lp_mint: Pubkey::find_program_address(&[LP_MINT_SEED, pool.as_ref()], &ID).0,
+                token_program: *token_program,
+                associated_token_program: anchor_spl::associated_token::ID,
+                system_program: anchor_lang::system_program::ID,
+            }
+            .to_account_metas(None),
+            data: instruction::InitializePool {}.data(),
+        }
+    }
+
+    /// `deposit` or `withdraw` of `provider` on the pool lending `mint`, `data` being the instruction data
+    fn liquidity_ix(provider: &Pubkey, mint: &Pubkey, token_program: &Pubkey, data: Vec<u8>) -> Instruction {
+        let pool = Pubkey::find_program_address(&[POOL_SEED, mint.as_ref()], &ID).0;
+        let lp_mint = Pubkey::find_program_address(&[LP_MINT_SEED, pool.as_ref()], &ID).0;
+
+        Instruction {
+            program_id: ID,
+            accounts: accounts::Liquidity {
+                provider: *provider,
+                pool,
+                mint: *mint,
+                lp_mint,
+                vault: ata(&pool, mint, token_program),
+                provider_ata: ata(provider, mint, token_program),
+                provider_lp_ata: ata(provider, &lp_mint, token_program),
+                token_program: *token_program,
+                associated_token_program: anchor_spl::associated_token::ID,
+                system_program: anchor_lang::system_program::ID,
+            }
+            .to_account_metas(None),
+            data,
+        }
+    }
+
+    fn initialize_protocol_ix(admin: &Pubkey, fee_bps: u16) -> Instruction {
+        Instruction {
+            program_id: ID,
+            accounts: accounts::InitializeProtocol {
+                admin: *admin,
+                config: Pubkey::find_program_address(&[CONFIG_SEED], &ID).0,
+                program: ID,
+                program_data: program_data_pda(),
+                system_program: anchor_lang::system_program::ID,
+            }
+            .to_account_metas(None),
+            data: instruction::InitializeProtocol { fee_bps }.data(),
+        }
+    }
+
+    /// Deploy the program with a fresh upgrade authority, returning it
+    fn deploy_program() -> Option<(LiteSVM, Keypair)> {
+        let Ok(program) = std::fs::read(PROGRAM_PATH) else {
+            println!("⚠️  Skipping: {} not found, build the program first", PROGRAM_PATH);
+            return None;
+        };
+
+        let mut svm = LiteSVM::new();
+        let admin = Keypair::new();
+        svm.airdrop(&admin.pubkey(), 10_000_000_000).unwrap();
+        add_upgradeable_program(&mut svm, &program, &key(&admin));
+
+        Some((svm, admin))
+    }
+
+    /// Deploy the program and initialize the protocol config, returning the admin
+    fn deploy() -> Option<(LiteSVM, Keypair)> {
+        let (mut svm, admin) = deploy_program()?;
+
+        let initialize_protocol = initialize_protocol_ix(&key(&admin), FEE_BPS);
+        send(&mut svm, vec![initialize_protocol], &admin, &[&admin]).expect("protocol setup should succeed");
+
+        Some((svm, admin))
+    }
+
+    fn admin_ix(admin: &Pubkey, data: Vec<u8>) -> Instruction {
+        Instruction {
+            program_id: ID,
+            accounts: accounts::AdminOnly {
+                admin: *admin,
+                config: Pubkey::find_program_address(&[CONFIG_SEED], &ID).0,
+            }
+            .to_account_metas(None),
+            data,
+        }
+    }
+
+    /// Open a pool of a `token_program` mint with `LIQUIDITY` deposited and fund a borrower for the fees
+    fn setup(token_program: Pubkey) -> Option<Env> {
+        setup_with_transfer_fee(token_program, None)
+    }
+
+    /// Same as `setup`, the mint charging a transfer fee of `transfer_fee_bps` if set (Token-2022 only)
+    fn setup_with_transfer_fee(token_program: Pubkey, transfer_fee_bps: Option<u16>) -> Option<Env> {
+        let (mut svm, admin) = deploy()?;
+
+        let provider = Keypair::new();
+        let borrower = Keypair::new();
+        for signer in [&provider, &borrower] {
+            svm.airdrop(&signer.pubkey(), 10_000_000_000).unwrap();
+        }
+
+        let mint = Pubkey::new_unique();
+        set_token_data(&mut svm, &mint, &token_program, mint_data(&key(&admin), transfer_fee_bps));
+
+        let initialize_pool = initialize_pool_ix(&key(&admin), &mint, &token_program);
+        send(&mut svm, vec![initialize_pool], &admin, &[&admin]).expect("pool setup should succeed");
+
+        // Token accounts go through the ATA program so they get the extensions their mint requires
+        let provider_ata = ata(&key(&provider), &mint, &token_program);
+        let borrower_ata = ata(&key(&borrower), &mint, &token_program);
+        let mut funding = Vec::new();
+        for (owner, account, amount) in [(&provider, provider_ata, LIQUIDITY), (&borrower, borrower_ata, BORROWER_FUNDS)] {
+            funding.push(create_associated_token_account(&key(&admin), &key(owner), &mint, &token_program));
+            funding.push(
+                spl_token_2022::instruction::mint_to(&token_program, &mint, &account, &key(&admin), &[], amount).unwrap(),
+            );
+        }
+        send(&mut svm, funding, &admin, &[&admin]).expect("funding should succeed");
+
+        let pool = Pubkey::find_program_address(&[POOL_SEED, mint.as_ref()], &ID).0;
+        let vault = ata(&pool, &mint, &token_program);
+
+        let deposit = liquidity_ix(&key(&provider), &mint, &token_program, instruction::Deposit { amount: LIQUIDITY }.data());
+        send(&mut svm, vec![deposit], &provider, &[&provider]).expect("deposit should succeed");
+
+        let env = Env { svm, admin, borrower, mint, token_program, pool, vault };
+        let deposited = amount_received(transfer_fee(&env).as_ref(), LIQUIDITY).unwrap();
+        assert_eq!(token_balance(&env.svm, &vault), deposited);
+
+        Some(env)
+    }
+
+    fn sol_pool_pda() -> Pubkey {
+        Pubkey::find_program_address(&[POOL_SEED, SOL_POOL_MINT.as_ref()], &ID).0
+    }
+
+    fn sol_vault_pda() -> Pubkey {
+        Pubkey::find_program_address(&[SOL_VAULT_SEED, sol_pool_pda().as_ref()], &ID).0
+    }
+
+    /// Open the SOL pool with `LIQUIDITY` lamports deposited by a new provider
+    fn open_sol_pool(svm: &mut LiteSVM, admin: &Keypair) {
+        let provider = Keypair::new();
+        svm.airdrop(&provider.pubkey(), 10_000_000_000).unwrap();
+
+        let pool = sol_pool_pda();
+        let vault = sol_vault_pda();
+        let lp_mint = Pubkey::find_program_address(&[LP_MINT_SEED, pool.as_ref()], &ID).0;
+
+        let initialize_sol_pool = Instruction {
+            program_id: ID,
+            accounts: accounts::InitializeSolPool {
+                admin: key(admin),
+                config: Pubkey::find_program_address(&[CONFIG_SEED], &ID).0,
+                pool,
+                vault,
+                lp_mint,
+                token_program: anchor_spl::token::ID,
+                system_program: anchor_lang::system_program::ID,
+            }
+            .to_account_metas(None),
+            data: instruction::InitializeSolPool {}.data(),
+        };
+        send(svm, vec![initialize_sol_pool], admin, &[admin]).expect("SOL pool setup should succeed");
+
+        let deposit_sol = Instruction {
+            program_id: ID,
+            accounts: accounts::SolLiquidity {
+                provider: key(&provider),
+                pool,
+                vault,
+                lp_mint,
+                provider_lp_ata: ata(&key(&provider), &lp_mint, &anchor_spl::token::ID),
+                token_program: anchor_spl::token::ID,
+                associated_token_program: anchor_spl::associated_token::ID,
+                system_program: anchor_lang::system_program::ID,
+            }
+            .to_account_metas(None),
+            data: instruction::DepositSol { amount: LIQUIDITY }.data(),
+        };
+        send(svm, vec![deposit_sol], &provider, &[&provider]).expect("SOL deposit should succeed");
+    }
+
+    /// Deploy the program and open the SOL pool
+    fn setup_sol() -> Option<SolEnv> {
+        let (mut svm, admin) = deploy()?;
+        open_sol_pool(&mut svm, &admin);
+
+        let borrower = Keypair::new();
+        svm.airdrop(&borrower.pubkey(), 10_000_000_000).unwrap();
+
+        Some(SolEnv { svm, admin, borrower, pool: sol_pool_pda(), vault: sol_vault_pda() })
+    }
+
+    fn borrow_sol_ix(borrower: &Pubkey, amount: u64) -> Instruction {
+        let pool = sol_pool_pda();
+
+        Instruction {
+            program_id: ID,
+            accounts: accounts::BorrowSol {
+                borrower: *borrower,
+                pool,
+                config: Pubkey::find_program_address(&[CONFIG_SEED], &ID).0,
+                loan: Pubkey::find_program_address(&[LOAN_SEED, pool.as_ref(), borrower.as_ref()], &ID).0,
+                vault: sol_vault_pda(),
+                instructions: INSTRUCTIONS_SYSVAR_ID,
+                system_program: anchor_lang::system_program::ID,
+            }
+            .to_account_metas(None),
+            data: instruction::BorrowSol { borrow_amount: amount }.data(),
+        }
+    }
+
+    fn repay_sol_ix(borrower: &Pubkey) -> Instruction {
+        let pool = sol_pool_pda();
+
+        Instruction {
+            program_id: ID,
+            accounts: accounts::RepaySol {
+                borrower: *borrower,
+                pool,
+                config: Pubkey::find_program_address(&[CONFIG_SEED], &ID).0,
+                loan: Pubkey::find_program_address(&[LOAN_SEED, pool.as_ref(), borrower.as_ref()], &ID).0,
+                vault: sol_vault_pda(),
+                instructions: INSTRUCTIONS_SYSVAR_ID,
+                system_program: anchor_lang::system_program::ID,
+            }
+            .to_account_metas(None),
+            data: instruction::RepaySol {}.data(),
+        }
+    }
+
+    fn lamports(svm: &LiteSVM, key: &Pubkey) -> u64 {
+        svm.get_account(&address(key)).map_or(0, |account| account.lamports)
+    }
+
+    /// `update_pool` keeping the default fee of the pools
+    fn update_pool_ix(
+        admin: &Pubkey,
+        pool: &Pubkey,
+        max_borrow: u64,
+        max_utilization_bps: u16,
+        transfer_fee_mode: TransferFeeMode,
+    ) -> Instruction {
+        Instruction {
+            program_id: ID,
+            accounts: accounts::UpdatePool {
+                admin: *admin,
+                config: Pubkey::find_program_address(&[CONFIG_SEED], &ID).0,
+                pool: *pool,
+            }
+            .to_account_metas(None),
+            data: instruction::UpdatePool {
+                fee_bps: FEE_BPS,
+                fee_rounding: Rounding::Up,
+                min_fee: 0,
+                max_borrow,
+                max_utilization_bps,
+                transfer_fee_mode,
+            }
+            .data(),
+        }
+    }
+
+    fn set_transfer_fee_mode(env: &mut Env, transfer_fee_mode: TransferFeeMode) {
+        let update_pool = update_pool_ix(&key(&env.admin), &env.pool, 0, 0, transfer_fee_mode);
+        let admin = env.admin.insecure_clone();
+        send(&mut env.svm, vec![update_pool], &admin, &[&admin]).expect("pool update should succeed");
+    }
+
+    fn quote_ix(env: &Env, amount: u64) -> Instruction {
+        Instruction {
+            program_id: ID,
+            accounts: accounts::Quote {
+                pool: env.pool,
+                mint: env.mint,
+                vault: env.vault,
+                token_program: env.token_program,
+            }
+            .to_account_metas(None),
+            data: instruction::Quote { amount }.data(),
+        }
+    }
+
+    fn quote_sol_ix(amount: u64) -> Instruction {
+        Instruction {
+            program_id: ID,
+            accounts: accounts::QuoteSol { pool: sol_pool_pda(), vault: sol_vault_pda() }.to_account_metas(None),
+            data: instruction::QuoteSol { amount }.data(),
+        }
+    }
+
+    /// Simulate a `quote` or `quote_sol` and decode the quote it returns
+    fn simulate_quote(svm: &LiteSVM, ix: Instruction, payer: &Keypair) -> LoanQuote {
+        let tx = Transaction::new_signed_with_payer(&[sdk_instruction(ix)], Some(&payer.pubkey()), &[payer], svm.latest_blockhash());
+        let simulated = svm.simulate_transaction(tx).expect("quote should succeed");
+        let return_data = simulated.meta.return_data;
+        assert_eq!(return_data.program_id, address(&ID));
+        LoanQuote::try_from_slice(&return_data.data).unwrap()
+    }
+
+    /// Assert that instruction `index` of the transaction failed with `error`
+    fn assert_protocol_error(result: TransactionResult, index: u8, error: ProtocolError) {
+        let failed = result.expect_err("transaction should fail");
+        assert_eq!(
+            failed.err,
+            TransactionError::InstructionError(index, InstructionError::Custom(error.into())),
+            "unexpected error, logs: {:#?}",
+            failed.meta.logs
+        );
+    }
+
+    /// Run a borrow settled by its repay in the same transaction and check the settlement
+    fn check_borrow_and_repay(mut env: Env) {
+        let amount = 100_000_000;
+        let fee = amount * FEE_BPS as u64 / 10_000;
+        let borrower = env.borrower.insecure_clone();
+        let borrower_ata = ata(&key(&borrower), &env.mint, &env.token_program);
+        let vault_before = token_balance(&env.svm, &env.vault);
+        let lamports_before = lamports(&env.svm, &key(&borrower));
+
+        let ixs = vec![borrow_ix(&env, amount), repay_ix(&env)];
+        let meta = send(&mut env.svm, ixs, &borrower, &[&borrower]).expect("flash loan should succeed");
+        println!("   ✅ Flash loan used {} compute units", meta.compute_units_consumed);
+
+        // The borrower pays the fee, grossed up by the mint transfer fee if there is one
+        let transfer_fee = transfer_fee(&env);
+        let sent = amount_to_send(transfer_fee.as_ref(), amount + fee).unwrap();
+        let received = amount_received(transfer_fee.as_ref(), amount).unwrap();
+        assert_eq!(token_balance(&env.svm, &borrower_ata), BORROWER_FUNDS + received - sent);
+
+        // The vault nets at least the principal plus the fee
+        let vault_after = token_balance(&env.svm, &env.vault);
+        assert_eq!(vault_after, vault_before - amount + amount_received(transfer_fee.as_ref(), sent).unwrap());
+        assert!(vault_after >= vault_before + fee, "The vault should earn the whole fee");
+
+        // The loan receipt is closed and the pool statistics are updated
+        let loan = Pubkey::find_program_address(&[LOAN_SEED, env.pool.as_ref(), key(&borrower).as_ref()], &ID).0;
+        assert!(
+            env.svm.get_account(&address(&loan)).is_none_or(|account| account.lamports == 0),
+            "Loan receipt should be closed"
+        );
+        assert_eq!(
+            lamports(&env.svm, &key(&borrower)),
+            lamports_before - 5_000,
+            "The receipt rent should be refunded, leaving only the signature fee"
+        );
+        let pool_account = env.svm.get_account(&address(&env.pool)).unwrap();
+        let pool = Pool::try_deserialize(&mut pool_account.data.as_slice()).unwrap();
+        assert_eq!(pool.outstanding, 0);
+        assert_eq!(pool.total_borrowed, amount);
+        assert_eq!(pool.lp_fees, fee, "Without a protocol share the whole fee goes to the liquidity providers");
+        assert_eq!(pool.protocol_fees, 0);
+        assert_eq!(pool.loan_count, 1);
+    }
+
+    /// Test a borrow settled by its repay in the same transaction
+    #[test]
+    fn test_borrow_and_repay() {
+        println!("🚀 Testing Borrow and Repay");
+        let Some(env) = setup(anchor_spl::token::ID) else { return };
+
+        check_borrow_and_repay(env);
+
+        println!("✅ Borrow and repay test passed");
+    }
+
+    /// Test a flash loan of a Token-2022 mint
+    #[test]
+    fn test_token_2022_borrow_and_repay() {
+        println!("🚀 Testing Token-2022 Borrow and Repay");
+        let Some(env) = setup(spl_token_2022::ID) else { return };
+
+        check_borrow_and_repay(env);
+
+        println!("✅ Token-2022 borrow and repay test passed");
+    }
+
+    /// Test that pools refuse to lend transfer fee mints unless they gross up the repay
+    #[test]
+    fn test_transfer_fee_refused() {
+        println!("🚀 Testing Transfer Fee Refused");
+        let Some(mut env) = setup_with_transfer_fee(spl_token_2022::ID, Some(100)) else { return };
+
+        let ixs = vec![borrow_ix(&env, 1_000_000), repay_ix(&env)];
+        let borrower = env.borrower.insecure_clone();
+        assert_protocol_error(send(&mut env.svm, ixs, &borrower, &[&borrower]), 0, ProtocolError::TransferFeeNotSupported);
+
+        println!("✅ Transfer fee refused test passed");
+    }
+
+    /// Test that grossed up repays leave the vault whole despite the transfer fee
+    #[test]
+    fn test_transfer_fee_gross_up() {
+        println!("🚀 Testing Transfer Fee Gross-Up");
+        let Some(mut env) = setup_with_transfer_fee(spl_token_2022::ID, Some(100)) else { return };
+
+        set_transfer_fee_mode(&mut env, TransferFeeMode::GrossUp);
+        check_borrow_and_repay(env);
+
+        println!("✅ Transfer fee gross-up test passed");
+    }
+
+    /// Test the quote returned through the return data, and that it matches what `repay` takes
+    #[test]
+    fn test_quote() {
+        println!("🚀 Testing Quote");
+        let Some(mut env) = setup_with_transfer_fee(spl_token_2022::ID, Some(100)) else { return };
+        set_transfer_fee_mode(&mut env, TransferFeeMode::GrossUp);
+
+        let amount = 100_000_000;
+        let quote = simulate_quote(&env.svm, quote_ix(&env, amount), &env.borrower);
+
+        let fee = amount * FEE_BPS as u64 / 10_000;
+        let vault_balance = token_balance(&env.svm, &env.vault);
+        assert_eq!(quote.fee, fee);
+        assert_eq!(quote.total, amount_to_send(transfer_fee(&env).as_ref(), amount + fee).unwrap());
+        assert_eq!(quote.available, vault_balance);
+
+        // The borrower pays exactly the quoted total
+        let borrower = env.borrower.insecure_clone();
+        let borrower_ata = ata(&key(&borrower), &env.mint, &env.token_program);
+        let received = amount_received(transfer_fee(&env).as_ref(), amount).unwrap();
+        let ixs = vec![borrow_ix(&env, amount), repay_ix(&env)];
+        send(&mut env.svm, ixs, &borrower, &[&borrower]).expect("flash loan should succeed");
+        assert_eq!(token_balance(&env.svm, &borrower_ata), BORROWER_FUNDS + received - quote.total);
+
+        println!("✅ Quote test passed");
+    }
+
+    fn set_paused_ix(authority: &Pubkey, paused: bool) -> Instruction {
+        Instruction {
+            program_id: ID,
+            accounts: accounts::SetPaused {
+                authority: *authority,
+                config: Pubkey::find_program_address(&[CONFIG_SEED], &ID).0,
+            }
+            .to_account_metas(None),
+            data: instruction::SetPaused { paused }.data(),
+        }
+    }
+
+    fn read_config(svm: &LiteSVM) -> ProtocolConfig {
+        let config = Pubkey::find_program_address(&[CONFIG_SEED], &ID).0;
+        let account = svm.get_account(&address(&config)).expect("config should exist");
+        ProtocolConfig::try_deserialize(&mut account.data.as_slice()).unwrap()
+    }
+
+    /// Test that only the upgrade authority of the program can initialize the protocol
+    #[test]
+    fn test_initialize_protocol_authority() {
+        println!("🚀 Testing Protocol Initialization Authority");
+        let Some((mut svm, admin)) = deploy_program() else { return };
+
+        let intruder = Keypair::new();
+        svm.airdrop(&intruder.pubkey(), 10_000_000_000).unwrap();
+        let ix = initialize_protocol_ix(&key(&intruder), FEE_BPS);
+        assert_protocol_error(send(&mut svm, vec![ix], &intruder, &[&intruder]), 0, ProtocolError::Unauthorized);
+
+        let ix = initialize_protocol_ix(&key(&admin), MAX_FEE_BPS + 1);
+        assert_protocol_error(send(&mut svm, vec![ix], &admin, &[&admin]), 0, ProtocolError::InvalidFee);
+
+        let ix = initialize_protocol_ix(&key(&admin), FEE_BPS);
+        send(&mut svm, vec![ix], &admin, &[&admin]).expect("protocol setup should succeed");
+        let config = read_config(&svm);
+        assert_eq!(config.admin, key(&admin));
+        assert_eq!(config.fee_bps, FEE_BPS);
+
+        println!("✅ Protocol initialization authority test passed");
+    }
+
+    /// Test that the admin instructions reject other signers and out of range fees
+    #[test]
+    fn test_admin_instructions() {
+        println!("🚀 Testing Admin Instructions");
+        let Some((mut svm, admin)) = deploy() else { return };
+
+        let intruder = Keypair::new();
+        svm.airdrop(&intruder.pubkey(), 10_000_000_000).unwrap();
+        let ix = admin_ix(&key(&intruder), instruction::UpdateFee { fee_bps: 0 }.data());
+        assert_protocol_error(send(&mut svm, vec![ix], &intruder, &[&intruder]), 0, ProtocolError::Unauthorized);
+        let ix = admin_ix(&key(&intruder), instruction::SetAdmin { new_admin: key(&intruder) }.data());
+        assert_protocol_error(send(&mut svm, vec![ix], &intruder, &[&intruder]), 0, ProtocolError::Unauthorized);
+
+        let ix = admin_ix(&key(&admin), instruction::UpdateFee { fee_bps: MAX_FEE_BPS + 1 }.data());
+        assert_protocol_error(send(&mut svm, vec![ix], &admin, &[&admin]), 0, ProtocolError::InvalidFee);
+        assert_eq!(read_config(&svm).fee_bps, FEE_BPS);
+
+        let ix = admin_ix(&key(&admin), instruction::UpdateFee { fee_bps: MAX_FEE_BPS }.data());
+        send(&mut svm, vec![ix], &admin, &[&admin]).expect("fee update should succeed");
+        assert_eq!(read_config(&svm).fee_bps, MAX_FEE_BPS);
+
+        // Handing over the admin role locks the previous admin out
+        let new_admin = Keypair::new();
+        svm.airdrop(&new_admin.pubkey(), 10_000_000_000).unwrap();
+        let ix = admin_ix(&key(&admin), instruction::SetAdmin { new_admin: key(&new_admin) }.data());
+        send(&mut svm, vec![ix], &admin, &[&admin]).expect("admin update should succeed");
+        assert_eq!(read_config(&svm).admin, key(&new_admin));
+
+        let ix = admin_ix(&key(&admin), instruction::UpdateFee { fee_bps: FEE_BPS }.data());
+        assert_protocol_error(send(&mut svm, vec![ix], &admin, &[&admin]), 0, ProtocolError::Unauthorized);
+        let ix = admin_ix(&key(&new_admin), instruction::UpdateFee { fee_bps: FEE_BPS }.data());
+        send(&mut svm, vec![ix], &new_admin, &[&new_admin]).expect("fee update by the new admin should succeed");
+
+        println!("✅ Admin instructions test passed");
+    }
+
+    /// Test that the guardian and the admin can pause new loans, and nobody else
+    #[test]
+    fn test_pause() {
+        println!("🚀 Testing Pause");
+        let Some(mut env) = setup(anchor_spl::token::ID) else { return };
+
+        let admin = env.admin.insecure_clone();
+        let borrower = env.borrower.insecure_clone();
+        let guardian = Keypair::new();
+        env.svm.airdrop(&guardian.pubkey(), 10_000_000_000).unwrap();
+        let ix = admin_ix(&key(&admin), instruction::SetGuardian { guardian: key(&guardian) }.data());
+        send(&mut env.svm, vec![ix], &admin, &[&admin]).expect("guardian update should succeed");
+        open_sol_pool(&mut env.svm, &admin);
+
+        let ix = set_paused_ix(&key(&borrower), true);
+        assert_protocol_error(send(&mut env.svm, vec![ix], &borrower, &[&borrower]), 0, ProtocolError::Unauthorized);
+        assert!(!read_config(&env.svm).paused);
+
+        let ix = set_paused_ix(&key(&guardian), true);
+        send(&mut env.svm, vec![ix], &guardian, &[&guardian]).expect("guardian pause should succeed");
+        assert!(read_config(&env.svm).paused);
+
+        let ixs = vec![borrow_ix(&env, 1_000_000), repay_ix(&env)];
+        assert_protocol_error(send(&mut env.svm, ixs, &borrower, &[&borrower]), 0, ProtocolError::Paused);
+        let ixs = vec![borrow_sol_ix(&key(&borrower), 1_000_000), repay_sol_ix(&key(&borrower))];
+        assert_protocol_error(send(&mut env.svm, ixs, &borrower, &[&borrower]), 0, ProtocolError::Paused);
+
+        // Only the admin and the guardian can lift the pause either
+        let ix = set_paused_ix(&key(&borrower), false);
+        assert_protocol_error(send(&mut env.svm, vec![ix], &borrower, &[&borrower]), 0, ProtocolError::Unauthorized);
+        let ix = set_paused_ix(&key(&admin), false);
+        send(&mut env.svm, vec![ix], &admin, &[&admin]).expect("admin unpause should succeed");
+        assert!(!read_config(&env.svm).paused);
+
+        let ixs = vec![borrow_ix(&env, 2_000_000), repay_ix(&env)];
+        send(&mut env.svm, ixs, &borrower, &[&borrower]).expect("flash loan should succeed once unpaused");
+
+        let ix = set_paused_ix(&key(&admin), true);
+        send(&mut env.svm, vec![ix], &admin, &[&admin]).expect("admin pause should succeed");
+        assert!(read_config(&env.svm).paused);
+
+        let Some(_) = add_receiver(&mut env, 0) else { return };
+        let ixs = vec![receiver_flash_loan_ix(&env, 1_000_000, vec![])];
+        assert_protocol_error(send(&mut env.svm, ixs, &borrower, &[&borrower]), 0, ProtocolError::Paused);
+
+        let ix = set_paused_ix(&key(&guardian), false);
+        send(&mut env.svm, vec![ix], &guardian, &[&guardian]).expect("guardian unpause should succeed");
+        assert!(!read_config(&env.svm).paused);
+
+        println!("✅ Pause test passed");
+    }
+
+    /// Test that a loan opened before a pause can still be repaid
+    #[test]
+    fn test_repay_while_paused() {
+        println!("🚀 Testing Repay While Paused");
+        let Some(mut env) = setup(anchor_spl::token::ID) else { return };
+
+        let admin = env.admin.insecure_clone();
+        let borrower = env.borrower.insecure_clone();
+        let amount = 100_000_000;
+        let fee = amount * FEE_BPS as u64 / 10_000;
+        let vault_before = token_balance(&env.svm, &env.vault);
+
+        let ixs = vec![borrow_ix(&env, amount), set_paused_ix(&key(&admin), true), repay_ix(&env)];
+        send(&mut env.svm, ixs, &borrower, &[&borrower, &admin]).expect("repay should succeed while paused");
+
+        assert!(read_config(&env.svm).paused);
+        assert_eq!(token_balance(&env.svm, &env.vault), vault_before + fee);
+        assert_eq!(lamports(&env.svm, &loan_pda(&env)), 0, "Loan receipt should be closed");
+
+        println!("✅ Repay while paused test passed");
+    }
+
+    /// Test the borrow caps of a token pool and the loans its quote says are available
+    #[test]
+    fn test_borrow_caps() {
+        println!("🚀 Testing Borrow Caps");
+        let Some(mut env) = setup(anchor_spl::token::ID) else { return };
+
+        let admin = env.admin.insecure_clone();
+        let borrower = env.borrower.insecure_clone();
+        let max_borrow = 100_000_000;
+
+        let ix = update_pool_ix(&key(&admin), &env.pool, max_borrow, 0, TransferFeeMode::Refuse);
+        send(&mut env.svm, vec![ix], &admin, &[&admin]).expect("pool update should succeed");
+        let available = simulate_quote(&env.svm, quote_ix(&env, 1), &borrower).available;
+        assert_eq!(available, max_borrow);
+
+        let ixs = vec![borrow_ix(&env, available + 1), repay_ix(&env)];
+        assert_protocol_error(send(&mut env.svm, ixs, &borrower, &[&borrower]), 0, ProtocolError::BorrowCapExceeded);
+        let ixs = vec![borrow_ix(&env, available), repay_ix(&env)];
+        send(&mut env.svm, ixs, &borrower, &[&borrower]).expect("loan at the borrow cap should succeed");
+
+        // A quarter of the vault can be lent at once
+        let ix = update_pool_ix(&key(&admin), &env.pool, 0, 2_500, TransferFeeMode::Refuse);
+        send(&mut env.svm, vec![ix], &admin, &[&admin]).expect("pool update should succeed");
+        let available = simulate_quote(&env.svm, quote_ix(&env, 1), &borrower).available;
+        assert_eq!(available, token_balance(&env.svm, &env.vault) / 4);
+
+        let ixs = vec![borrow_ix(&env, available + 1), repay_ix(&env)];
+        assert_protocol_error(send(&mut env.svm, ixs, &borrower, &[&borrower]), 0, ProtocolError::UtilizationCapExceeded);
+        let ixs = vec![borrow_ix(&env, available), repay_ix(&env)];
+        send(&mut env.svm, ixs, &borrower, &[&borrower]).expect("loan at the utilization cap should succeed");
+
+        println!("✅ Borrow caps test passed");
+    }
+
+    /// Test the borrow caps of the SOL pool and the loans its quote says are available
+    #[test]
+    fn test_sol_borrow_caps() {
+        println!("🚀 Testing SOL Borrow Caps");
+        let Some(mut env) = setup_sol() else { return };
+
+        let admin = env.admin.insecure_clone();
+        let borrower = env.borrower.insecure_clone();
+        let max_borrow = 100_000_000;
+
+        let ix = update_pool_ix(&key(&admin), &env.pool, max_borrow, 0, TransferFeeMode::Refuse);
+        send(&mut env.svm, vec![ix], &admin, &[&admin]).expect("pool update should succeed");
+        let available = simulate_quote(&env.svm, quote_sol_ix(1), &borrower).available;
+        assert_eq!(available, max_borrow);
+
+        let ixs = vec![borrow_sol_ix(&key(&borrower), available + 1), repay_sol_ix(&key(&borrower))];
+        assert_protocol_error(send(&mut env.svm, ixs, &borrower, &[&borrower]), 0, ProtocolError::BorrowCapExceeded);
+        let ixs = vec![borrow_sol_ix(&key(&borrower), available), repay_sol_ix(&key(&borrower))];
+        send(&mut env.svm, ixs, &borrower, &[&borrower]).expect("SOL loan at the borrow cap should succeed");
+
+        // A quarter of the lamports above the rent-exempt minimum can be lent at once
+        let ix = update_pool_ix(&key(&admin), &env.pool, 0, 2_500, TransferFeeMode::Refuse);
+        send(&mut env.svm, vec![ix], &admin, &[&admin]).expect("pool update should succeed");
+        let available = simulate_quote(&env.svm, quote_sol_ix(1), &borrower).available;
+        let vault_len = env.svm.get_account(&address(&env.vault)).unwrap().data.len();
+        let rent = env.svm.minimum_balance_for_rent_exemption(vault_len);
+        assert_eq!(available, (lamports(&env.svm, &env.vault) - rent) / 4);
+
+        let ixs = vec![borrow_sol_ix(&key(&borrower), available + 1), repay_sol_ix(&key(&borrower))];
+        assert_protocol_error(send(&mut env.svm, ixs, &borrower, &[&borrower]), 0, ProtocolError::UtilizationCapExceeded);
+        let ixs = vec![borrow_sol_ix(&key(&borrower), available), repay_sol_ix(&key(&borrower))];
+        send(&mut env.svm, ixs, &borrower, &[&borrower]).expect("SOL loan at the utilization cap should succeed");
+
+        println!("✅ SOL borrow caps test passed");
+    }
+
+    /// Test the protocol share of the fees and their collection by the admin
+    #[test]
+    fn test_collect_protocol_fees() {
+        println!("🚀 Testing Protocol Fee Collection");
+        let Some(mut env) = setup(anchor_spl::token::ID) else { return };
+
+        let admin = env.admin.insecure_clone();
+        let config = Pubkey::find_program_address(&[CONFIG_SEED], &ID).0;
+        let treasury = Pubkey::new_unique();
+        let admin_only = accounts::AdminOnly { admin: key(&admin), config }.to_account_metas(None);
+        let setup_ixs = vec![
+            Instruction {
+                program_id: ID,
+                accounts: admin_only.clone(),
+                data: instruction::UpdateProtocolShare { protocol_share_bps: 2_000 }.data(),
+            },
+            Instruction {
+                program_id: ID,
+                accounts: admin_only,
+                data: instruction::SetTreasury { treasury }.data(),
+            },
+        ];
+        send(&mut env.svm, setup_ixs, &admin, &[&admin]).expect("fee share setup should succeed");
+
+        let amount = 100_000_000;
+        let fee = amount * FEE_BPS as u64 / 10_000;
+        let borrower = env.borrower.insecure_clone();
+        let ixs = vec![borrow_ix(&env, amount), repay_ix(&env)];
+        send(&mut env.svm, ixs, &borrower, &[&borrower]).expect("flash loan should succeed");
+
+        // A fifth of the fee is set aside for the protocol, in the vault until collected
+        let read_pool = |env: &Env| {
+            let pool_account = env.svm.get_account(&address(&env.pool)).unwrap();
+            Pool::try_deserialize(&mut pool_account.data.as_slice()).unwrap()
+        };
+        let pool = read_pool(&env);
+        assert_eq!(pool.protocol_fees, fee / 5);
+        assert_eq!(pool.lp_fees, fee - fee / 5);
+        assert_eq!(pool.unclaimed_protocol_fees, fee / 5);
+        assert_eq!(token_balance(&env.svm, &env.vault), LIQUIDITY + fee);
+
+        let treasury_ata = ata(&treasury, &env.mint, &env.token_program);
+        let collect = |authority: &Keypair| Instruction {
+            program_id: ID,
+            accounts: accounts::CollectProtocolFees {
+                admin: key(authority),
+                config,
+                pool: env.pool,
+                mint: env.mint,
+                vault: env.vault,
+                treasury,
+                treasury_ata,
+                token_program: env.token_program,
+                associated_token_program: anchor_spl::associated_token::ID,
+                system_program: anchor_lang::system_program::ID,
+            }
+            .to_account_metas(None),
+            data: instruction::CollectProtocolFees {}.data(),
+        };
+
+        // Only the admin can collect
+        let ix = collect(&borrower);
+        assert_protocol_error(send(&mut env.svm, vec![ix], &borrower, &[&borrower]), 0, ProtocolError::Unauthorized);
+
+        let ix = collect(&admin);
+        send(&mut env.svm, vec![ix], &admin, &[&admin]).expect("collection should succeed");
+        assert_eq!(token_balance(&env.svm, &treasury_ata), fee / 5);
+        assert_eq!(token_balance(&env.svm, &env.vault), LIQUIDITY + fee - fee / 5, "The LP fees stay in the vault");
+
+        let pool = read_pool(&env);
+        assert_eq!(pool.unclaimed_protocol_fees, 0);
+        assert_eq!(pool.protocol_fees, fee / 5);
+
+        // Nothing is left to collect
+        env.svm.expire_blockhash();
+        let ix = collect(&admin);
+        assert_protocol_error(send(&mut env.svm, vec![ix], &admin, &[&admin]), 0, ProtocolError::InvalidAmount);
+
+        println!("✅ Protocol fee collection test passed");
+    }
+
+    /// Test that a pool cannot be seeded with dust and that a donation to its vault cannot steal the next deposit
+    #[test]
+    fn test_first_deposit_inflation() {
+        println!("🚀 Testing First Deposit Inflation");
+        let Some((mut svm, admin)) = deploy() else { return };
+
+        let token_program = anchor_spl::token::ID;
+        let mint = Pubkey::new_unique();
+        set_token_data(&mut svm, &mint, &token_program, mint_data(&key(&admin), None));
+        let initialize_pool = initialize_pool_ix(&key(&admin), &mint, &token_program);
+        send(&mut svm, vec![initialize_pool], &admin, &[&admin]).expect("pool setup should succeed");
+
+        let donation = 1_000_000_000;
+        let deposit = 1_900_000_000;
+        let (attacker, victim) = (Keypair::new(), Keypair::new());
+        let mut funding = Vec::new();
+        for (owner, amount) in [(&attacker, MIN_FIRST_DEPOSIT + donation), (&victim, deposit)] {
+            svm.airdrop(&owner.pubkey(), 10_000_000_000).unwrap();
+            funding.push(create_associated_token_account(&key(&admin), &key(owner), &mint, &token_program));
+            funding.push(
+                spl_token_2022::instruction::mint_to(&token_program, &mint, &ata(&key(owner), &mint, &token_program), &key(&admin), &[], amount)
+                    .unwrap(),
+            );
+        }
+        send(&mut svm, funding, &admin, &[&admin]).expect("funding should succeed");
+
+        // Dust cannot seed the pool
+        let seed = |amount| liquidity_ix(&key(&attacker), &mint, &token_program, instruction::Deposit { amount }.data());
+        assert_protocol_error(send(&mut svm, vec![seed(MIN_FIRST_DEPOSIT - 1)], &attacker, &[&attacker]), 0, ProtocolError::DepositTooSmall);
+        send(&mut svm, vec![seed(MIN_FIRST_DEPOSIT)], &attacker, &[&attacker]).expect("first deposit should succeed");
+
+        // The attacker inflates the share price by sending tokens straight to the vault
+        let pool = Pubkey::find_program_address(&[POOL_SEED, mint.as_ref()], &ID).0;
+        let vault = ata(&pool, &mint, &token_program);
+        let attacker_ata = ata(&key(&attacker), &mint, &token_program);
+        let donate = spl_token_2022::instruction::transfer_checked(
+            &token_program, &attacker_ata, &mint, &vault, &key(&attacker), &[], donation, DECIMALS,
+        )
+        .unwrap();
+        send(&mut svm, vec![donate], &attacker, &[&attacker]).expect("donation should succeed");
+
+        // The victim deposits and withdraws every share, losing only rounding dust
+        let victim_ix = |data| liquidity_ix(&key(&victim), &mint, &token_program, data);
+        send(&mut svm, vec![victim_ix(instruction::Deposit { amount: deposit }.data())], &victim, &[&victim])
+            .expect("victim deposit should succeed");
+        let lp_mint = Pubkey::find_program_address(&[LP_MINT_SEED, pool.as_ref()], &ID).0;
+        let shares = token_balance(&svm, &ata(&key(&victim), &lp_mint, &token_program));
+        assert!(shares > 0, "The victim should get shares");
+        send(&mut svm, vec![victim_ix(instruction::Withdraw { shares }.data())], &victim, &[&victim])
+            .expect("victim withdrawal should succeed");
+
+        let redeemed = token_balance(&svm, &ata(&key(&victim), &mint, &token_program));
+        assert!(redeemed >= deposit - deposit / 10_000, "The victim should lose at most 0.01%, redeemed {}", redeemed);
+
+        println!("✅ First deposit inflation test passed");
+    }
+
+    /// Test that pools cannot be opened for mints with extensions breaking the vault accounting
+    #[test]
+    fn test_unsupported_mint_extension() {
+        println!("🚀 Testing Unsupported Mint Extension");
+        let Some((mut svm, admin)) = deploy() else { return };
+
+        // A permanent delegate could move funds out of the vault at any time
+        let len = ExtensionType::try_calculate_account_len::<spl_token_2022::state::Mint>(&[ExtensionType::PermanentDelegate]).unwrap();
+        let mut data = vec![0; len];
+        let mut state = StateWithExtensionsMut::<spl_token_2022::state::Mint>::unpack_uninitialized(&mut data).unwrap();
+        state.init_extension::<PermanentDelegate>(true).unwrap().delegate = Some(key(&admin)).try_into().unwrap();
+        state.base = spl_token_2022::state::Mint { decimals: DECIMALS, is_initialized: true, ..Default::default() };
+        state.pack_base();
+        state.init_account_type().unwrap();
+
+        let mint = Pubkey::new_unique();
+        set_token_data(&mut svm, &mint, &spl_token_2022::ID, data);
+
+        let initialize_pool = initialize_pool_ix(&key(&admin), &mint, &spl_token_2022::ID);
+        assert_protocol_error(send(&mut svm, vec![initialize_pool], &admin, &[&admin]), 0, ProtocolError::UnsupportedMintExtension);
+
+        println!("✅ Unsupported mint extension test passed");
+    }
+
+    /// Test that the flash loan callback cannot target this program
+    #[test]
+    fn test_flash_loan_invalid_receiver() {
+        println!("🚀 Testing Flash Loan Invalid Receiver");
+        let Some(mut env) = setup(anchor_spl::token::ID) else { return };
+
+        let ixs = vec![flash_loan_ix(&env, &ID, 1_000_000)];
+        let borrower = env.borrower.insecure_clone();
+        assert_protocol_error(send(&mut env.svm, ixs, &borrower, &[&borrower]), 0, ProtocolError::InvalidReceiver);
+        assert_eq!(token_balance(&env.svm, &env.vault), LIQUIDITY);
+
+        println!("✅ Flash loan invalid receiver test passed");
+    }
+
+    /// Test a flash loan whose callback repays the principal and the fee
+    #[test]
+    fn test_flash_loan_callback() {
+        println!("🚀 Testing Flash Loan Callback");
+        let Some(mut env) = setup(anchor_spl::token::ID) else { return };
+
+        // The receiver only holds the fee, it gets the principal from the loan
+        let amount = 100_000_000;
+        let fee = amount * FEE_BPS as u64 / 10_000;
+        let Some(receiver_ata) = add_receiver(&mut env, fee) else { return };
+
+        let borrower = env.borrower.insecure_clone();
+        let ixs = vec![receiver_flash_loan_ix(&env, amount, vec![])];
+        let meta = send(&mut env.svm, ixs, &borrower, &[&borrower]).expect("flash loan should succeed");
+        println!("   ✅ Flash loan used {} compute units", meta.compute_units_consumed);
+
+        assert_eq!(token_balance(&env.svm, &env.vault), LIQUIDITY + fee);
+        assert_eq!(token_balance(&env.svm, &receiver_ata), 0);
+
+        let pool_account = env.svm.get_account(&address(&env.pool)).unwrap();
+        let pool = Pool::try_deserialize(&mut pool_account.data.as_slice()).unwrap();
+        assert_eq!(pool.outstanding, 0);
+        assert_eq!(pool.total_borrowed, amount);
+        assert_eq!(pool.lp_fees, fee);
+        assert_eq!(pool.loan_count, 1);
+
+        println!("✅ Flash loan callback test passed");
+    }
+
+    /// Test that a callback sending back less than the principal and the fee fails the flash loan
+    #[test]
+    fn test_flash_loan_callback_under_repay() {
+        println!("🚀 Testing Flash Loan Callback Under Repay");
+        let Some(mut env) = setup(anchor_spl::token::ID) else { return };
+
+        let amount = 100_000_000;
+        let fee = amount * FEE_BPS as u64 / 10_000;
+        let Some(receiver_ata) = add_receiver(&mut env, fee) else { return };
+
+        // One unit short of the fee
+        let borrower = env.borrower.insecure_clone();
+        let repaid = amount + fee - 1;
+        let ixs = vec![receiver_flash_loan_ix(&env, amount, repaid.to_le_bytes().to_vec())];
+        assert_protocol_error(send(&mut env.svm, ixs, &borrower, &[&borrower]), 0, ProtocolError::NotEnoughFunds);
+
+        assert_eq!(token_balance(&env.svm, &env.vault), LIQUIDITY);
+        assert_eq!(token_balance(&env.svm, &receiver_ata), fee);
+
+        println!("✅ Flash loan callback under repay test passed");
+    }
+
+    /// Test a loan borrowed and repaid by an allowed program through CPIs
+    #[test]
+    fn test_cpi_borrow() {
+        println!("🚀 Testing CPI Borrow");
+        let Some(mut env) = setup(anchor_spl::token::ID) else { return };
+        let Some(()) = add_cpi_borrower(&mut env, false) else { return };
+
+        let amount = 100_000_000;
+        let fee = amount * FEE_BPS as u64 / 10_000;
+        let borrower = env.borrower.insecure_clone();
+        let loan = loan_pda(&env);
+
+        // Programs have to be allowed to borrow through a CPI
+        let ixs = vec![cpi_borrow_ix(&env, amount, true), check_repaid_ix(&[loan])];
+        assert_protocol_error(send(&mut env.svm, ixs, &borrower, &[&borrower]), 0, ProtocolError::UnauthorizedCaller);
+
+        let Some(()) = add_cpi_borrower(&mut env, true) else { return };
+        let ixs = vec![cpi_borrow_ix(&env, amount, true), check_repaid_ix(&[loan])];
+        let meta = send(&mut env.svm, ixs, &borrower, &[&borrower]).expect("CPI flash loan should succeed");
+        println!("   ✅ CPI flash loan used {} compute units", meta.compute_units_consumed);
+
+        // The receipt is closed and the pool is free again
+        assert_eq!(token_balance(&env.svm, &env.vault), LIQUIDITY + fee);
+        assert_eq!(lamports(&env.svm, &loan), 0);
+        let pool_account = env.svm.get_account(&address(&env.pool)).unwrap();
+        let pool = Pool::try_deserialize(&mut pool_account.data.as_slice()).unwrap();
+        assert_eq!(pool.outstanding, 0);
+        assert_eq!(pool.lp_fees, fee);
+
+        println!("✅ CPI borrow test passed");
+    }
+
+    /// Test that a CPI loan cannot outlive its transaction
+    #[test]
+    fn test_cpi_borrow_settlement_check() {
+        println!("🚀 Testing CPI Borrow Settlement Check");
+        let Some(mut env) = setup(anchor_spl::token::ID) else { return };
+        let Some(()) = add_cpi_borrower(&mut env, true) else { return };
+
+        let amount = 100_000_000;
+        let borrower = env.borrower.insecure_clone();
+        let loan = loan_pda(&env);
+
+        // Without a later check of the receipt the borrow fails, even when the caller repays
+        let ixs = vec![cpi_borrow_ix(&env, amount, true)];
+        assert_protocol_error(send(&mut env.svm, ixs, &borrower, &[&borrower]), 0, ProtocolError::MissingRepayCheck);
+        let ixs = vec![check_repaid_ix(&[loan]), cpi_borrow_ix(&env, amount, true)];
+        assert_protocol_error(send(&mut env.svm, ixs, &borrower, &[&borrower]), 1, ProtocolError::MissingRepayCheck);
+        let ixs = vec![cpi_borrow_ix(&env, amount, true), check_repaid_ix(&[Pubkey::new_unique()])];
+        assert_protocol_error(send(&mut env.svm, ixs, &borrower, &[&borrower]), 0, ProtocolError::MissingRepayCheck);
+
+        // A caller that does not repay is caught by the check
+        let ixs = vec![cpi_borrow_ix(&env, amount, false), check_repaid_ix(&[loan])];
+        assert_protocol_error(send(&mut env.svm, ixs, &borrower, &[&borrower]), 1, ProtocolError::LoanNotRepaid);
+
+        // Nothing left the vault and no receipt stays open to block the pool
+        assert_eq!(token_balance(&env.svm, &env.vault), LIQUIDITY);
+        assert_eq!(lamports(&env.svm, &loan), 0);
+        let pool_account = env.svm.get_account(&address(&env.pool)).unwrap();
+        let pool = Pool::try_deserialize(&mut pool_account.data.as_slice()).unwrap();
+        assert_eq!(pool.outstanding, 0);
+
+        println!("✅ CPI borrow settlement check test passed");
+    }
+
+    /// Test that loans are settled by the kind of caller that opened them
+    #[test]
+    fn test_cpi_caller_mismatch() {
+        println!("🚀 Testing CPI Caller Mismatch");
+        let Some(mut env) = setup(anchor_spl::token::ID) else { return };
+        let Some(()) = add_cpi_borrower(&mut env, true) else { return };
+
+        let amount = 100_000_000;
+        let borrower = env.borrower.insecure_clone();
+        let loan = loan_pda(&env);
+
+        // A program cannot repay a loan opened at the top level
+        let ixs = vec![borrow_ix(&env, amount), cpi_repay_ix(&env), repay_ix(&env)];
+        assert_protocol_error(send(&mut env.svm, ixs, &borrower, &[&borrower]), 1, ProtocolError::CallerMismatch);
+
+        // A loan opened through a CPI cannot be repaid at the top level
+        let ixs = vec![cpi_borrow_ix(&env, amount, false), repay_ix(&env), check_repaid_ix(&[loan])];
+        assert_protocol_error(send(&mut env.svm, ixs, &borrower, &[&borrower]), 1, ProtocolError::CallerMismatch);
+
+        // Nor by its caller from another top-level instruction
+        let ixs = vec![cpi_borrow_ix(&env, amount, false), cpi_repay_ix(&env), check_repaid_ix(&[loan])];
+        assert_protocol_error(send(&mut env.svm, ixs, &borrower, &[&borrower]), 1, ProtocolError::RepayIndexMismatch);
+
+        assert_eq!(token_balance(&env.svm, &env.vault), LIQUIDITY);
+        assert_eq!(lamports(&env.svm, &loan), 0);
+
+        println!("✅ CPI caller mismatch test passed");
+    }
+
+    /// Test a CPI loan borrowed by a PDA of the calling program
+    #[test]
+    fn test_cpi_borrow_pda_borrower() {
+        println!("🚀 Testing CPI Borrow by a PDA");
+        let Some(mut env) = setup(anchor_spl::token::ID) else { return };
+        let Some(()) = add_cpi_borrower(&mut env, true) else { return };
+
+        // The PDA pays for its receipt and holds the tokens for the fee
+        let pda = cpi_borrower_pda();
+        let pda_ata = ata(&pda, &env.mint, &env.token_program);
+        env.svm.airdrop(&address(&pda), 1_000_000_000).unwrap();
+        let admin = env.admin.insecure_clone();
+        let funding = vec![
+            create_associated_token_account(&key(&admin), &pda, &env.mint, &env.token_program),
+            spl_token_2022::instruction::mint_to(&env.token_program, &env.mint, &pda_ata, &key(&admin), &[], BORROWER_FUNDS).unwrap(),
+        ];
+        send(&mut env.svm, funding, &admin, &[&admin]).expect("PDA funding should succeed");
+
+        let amount = 100_000_000;
+        let fee = amount * FEE_BPS as u64 / 10_000;
+        let loan = Pubkey::find_program_address(&[LOAN_SEED, env.pool.as_ref(), pda.as_ref()], &ID).0;
+        let pda_lamports = lamports(&env.svm, &pda);
+
+        let ixs = vec![cpi_pda_borrow_ix(&env, amount, true), check_repaid_ix(&[loan])];
+        let borrower = env.borrower.insecure_clone();
+        send(&mut env.svm, ixs, &borrower, &[&borrower]).expect("CPI flash loan of a PDA should succeed");
+
+        assert_eq!(token_balance(&env.svm, &env.vault), LIQUIDITY + fee);
+        assert_eq!(token_balance(&env.svm, &pda_ata), BORROWER_FUNDS - fee);
+        assert_eq!(lamports(&env.svm, &loan), 0);
+        assert_eq!(lamports(&env.svm, &pda), pda_lamports, "The receipt rent should be refunded to the PDA");
+
+        // A PDA borrower still needs the caller to repay
+        let ixs = vec![cpi_pda_borrow_ix(&env, amount, false), check_repaid_ix(&[loan])];
+        assert_protocol_error(send(&mut env.svm, ixs, &borrower, &[&borrower]), 1, ProtocolError::LoanNotRepaid);
+
+        println!("✅ CPI borrow by a PDA test passed");
+    }
+
+    /// Test a borrow without a repay
+    #[test]
+    fn test_missing_repay() {
+        println!("🚀 Testing Missing Repay");
+        let Some(mut env) = setup(anchor_spl::token::ID) else { return };
+
+        let ixs = vec![borrow_ix(&env, 1_000)];
+        let borrower = env.borrower.insecure_clone();
+        assert_protocol_error(send(&mut env.svm, ixs, &borrower, &[&borrower]), 0, ProtocolError::MissingRepayIx);
+
+        println!("✅ Missing repay test passed");
+    }
+
+    /// Test a repay without a borrow
+    #[test]
+    fn test_missing_borrow() {
+        println!("🚀 Testing Missing Borrow");
+        let Some(mut env) = setup(anchor_spl::token::ID) else { return };
+
+        // `repay` no longer creates the loan receipt, so without a borrow there is none to settle
+        let ixs = vec![repay_ix(&env)];
+        let borrower = env.borrower.insecure_clone();
+        let failed = send(&mut env.svm, ixs, &borrower, &[&borrower]).expect_err("repay without borrow should fail");
+        assert_eq!(
+            failed.err,
+            TransactionError::InstructionError(0, InstructionError::Custom(anchor_lang::error::ErrorCode::AccountNotInitialized.into())),
+            "unexpected error, logs: {:#?}",
+            failed.meta.logs
+        );
+
+        println!("✅ Missing borrow test passed");
+    }
+
+    /// Test a repay sending the funds back from another token account
+    #[test]
+    fn test_wrong_borrower_ata() {
+        println!("🚀 Testing Wrong Borrower ATA");
+        let Some(mut env) = setup(anchor_spl::token::ID) else { return };
+
+        let mut repay = repay_ix(&env);
+        repay.accounts[3].pubkey = Pubkey::new_unique();
+        let ixs = vec![borrow_ix(&env, 1_000), repay];
+        let borrower = env.borrower.insecure_clone();
+        assert_protocol_error(send(&mut env.svm, ixs, &borrower, &[&borrower]), 0, ProtocolError::InvalidBorrowerAta);
+
+        println!("✅ Wrong borrower ATA test passed");
+    }
+
+    /// Test a borrow placed after an instruction of a program that is not allowed to lead
+    #[test]
+    fn test_borrow_not_first() {
+        println!("🚀 Testing Borrow Not First");
+        let Some(mut env) = setup(anchor_spl::token::ID) else { return };
+
+        let borrower = env.borrower.insecure_clone();
+        let leading = anchor_lang::solana_program::system_instruction::transfer(&key(&borrower), &Pubkey::new_unique(), 1_000_000);
+        let ixs = vec![leading, borrow_ix(&env, 1_000), repay_ix(&env)];
+        assert_protocol_error(send(&mut env.svm, ixs, &borrower, &[&borrower]), 1, ProtocolError::InvalidIx);
+
+        println!("✅ Borrow not first test passed");
+    }
+
+    /// Test a repay from a borrower who cannot cover the fee
+    ///
+    /// The token transfer of the repay fails before the vault balance check, so the
+    /// error comes from the token program. That check cannot return
+    /// `ProtocolError::NotEnoughFunds` on its own: `withdraw` and the fee collection
+    /// are blocked while a loan is open, other loans on the vault have to be repaid
+    /// first, and the repay grosses up any transfer fee (which cannot change within
+    /// the transaction). `test_flash_loan_callback_under_repay` covers the error on
+    /// `flash_loan`, where the receiver chooses what it sends back.
+    #[test]
+    fn test_insufficient_repayment() {
+        println!("🚀 Testing Insufficient Repayment");
+        let Some(mut env) = setup(anchor_spl::token::ID) else { return };
+
+        // Leave the borrower without the tokens needed for the fee
+        let borrower = env.borrower.insecure_clone();
+        let borrower_ata = ata(&key(&borrower), &env.mint, &env.token_program);
+        set_token_account(&mut env.svm, &borrower_ata, &env.token_program, &env.mint, &key(&borrower), 0);
+
+        let ixs = vec![borrow_ix(&env, 100_000_000), repay_ix(&env)];
+        let failed = send(&mut env.svm, ixs, &borrower, &[&borrower]).expect_err("repay should fail");
+        assert_eq!(
+            failed.err,
+            TransactionError::InstructionError(1, InstructionError::Custom(spl_token::error::TokenError::InsufficientFunds as u32)),
+            "unexpected error, logs: {:#?}",
+            failed.meta.logs
+        );
+
+        // Nothing left the vault
+        assert_eq!(token_balance(&env.svm, &env.vault), LIQUIDITY);
+
+        println!("✅ Insufficient repayment test passed");
+    }
+
+    /// Test a SOL flash loan settled by its repay in the same transaction
+    #[test]
+    fn test_sol_borrow_and_repay() {
+        println!("🚀 Testing SOL Borrow and Repay");
+        let Some(mut env) = setup_sol() else { return };
+
+        let amount = 500_000_000;
+        let fee = amount * FEE_BPS as u64 / 10_000;
+        let borrower = env.borrower.insecure_clone();
+        let vault_before = lamports(&env.svm, &env.vault);
+        let borrower_before = lamports(&env.svm, &key(&borrower));
+
+        let ixs = vec![borrow_sol_ix(&key(&env.borrower), amount), repay_sol_ix(&key(&env.borrower))];
+        let meta = send(&mut env.svm, ixs, &borrower, &[&borrower]).expect("SOL flash loan should succeed");
+        println!("   ✅ SOL flash loan used {} compute units", meta.compute_units_consumed);
+
+        // The vault earns the fee, the borrower pays it on top of the transaction fee
+        assert_eq!(lamports(&env.svm, &env.vault), vault_before + fee);
+        assert!(lamports(&env.svm, &key(&borrower)) <= borrower_before - fee);
+
+        let pool_account = env.svm.get_account(&address(&env.pool)).unwrap();
+        let pool = Pool::try_deserialize(&mut pool_account.data.as_slice()).unwrap();
+        assert_eq!(pool.mint, SOL_POOL_MINT);
+        assert_eq!(pool.outstanding, 0);
+        assert_eq!(pool.total_borrowed, amount);
+        assert_eq!(pool.lp_fees, fee);
+
+        println!("✅ SOL borrow and repay test passed");
+    }
+
+    /// Test the SOL pool quote, and that it matches what `repay_sol` takes
+    #[test]
+    fn test_quote_sol() {
+        println!("🚀 Testing SOL Quote");
+        let Some(mut env) = setup_sol() else { return };
+
+        let amount = 500_000_000;
+        let quote = simulate_quote(&env.svm, quote_sol_ix(amount), &env.borrower);
+
+        // Lamports carry no transfer fee, and the rent-exempt minimum of the vault cannot be lent
+        let fee = amount * FEE_BPS as u64 / 10_000;
+        assert_eq!(quote.fee, fee);
+        assert_eq!(quote.total, amount + fee);
+        assert_eq!(quote.available, LIQUIDITY);
+
+        // The vault earns exactly the quoted fee
+        let borrower = env.borrower.insecure_clone();
+        let vault_before = lamports(&env.svm, &env.vault);
+        let ixs = vec![borrow_sol_ix(&key(&env.borrower), amount), repay_sol_ix(&key(&env.borrower))];
+        send(&mut env.svm, ixs, &borrower, &[&borrower]).expect("SOL flash loan should succeed");
+        assert_eq!(lamports(&env.svm, &env.vault), vault_before + quote.total - amount);
+
+        println!("✅ SOL quote test passed");
+    }
+
+    /// Test that SOL loans cannot leave the vault short
+    #[test]
+    fn test_sol_missing_repay() {
+        println!("🚀 Testing SOL Missing Repay");
+        let Some(mut env) = setup_sol() else { return };
+
+        let borrower = env.borrower.insecure_clone();
+        let vault_before = lamports(&env.svm, &env.vault);
+
+        let ixs = vec![borrow_sol_ix(&key(&env.borrower), 1_000_000)];
+        assert_protocol_error(send(&mut env.svm, ixs, &borrower, &[&borrower]), 0, ProtocolError::MissingRepayIx);
+
+        // Lending more than the vault holds above its rent-exempt minimum fails
+        let ixs = vec![borrow_sol_ix(&key(&env.borrower), LIQUIDITY + 1), repay_sol_ix(&key(&env.borrower))];
+        assert_protocol_error(send(&mut env.svm, ixs, &borrower, &[&borrower]), 0, ProtocolError::NotEnoughFunds);
+
+        assert_eq!(lamports(&env.svm, &env.vault), vault_before);
+
+        println!("✅ SOL missing repay test passed");
+    }
+
+    /// Test a token loan and a SOL loan in the same transaction
+    #[test]
+    fn test_token_and_sol_loans() {
+        println!("🚀 Testing Token and SOL Loans");
+        let Some(mut env) = setup(anchor_spl::token::ID) else { return };
+
+        let admin = env.admin.insecure_clone();
+        open_sol_pool(&mut env.svm, &admin);
+
+        let borrower = env.borrower.insecure_clone();
+        let (amount, sol_amount) = (100_000_000, 200_000_000);
+        let vault_before = token_balance(&env.svm, &env.vault);
+        let sol_vault_before = lamports(&env.svm, &sol_vault_pda());
+
+        // The SOL loan runs inside the token loan, each repay settling the latest open borrow on its own vault
+        let ixs = vec![
+            borrow_ix(&env, amount),
+            borrow_sol_ix(&key(&borrower), sol_amount),
+            repay_sol_ix(&key(&borrower)),
+            repay_ix(&env),
+        ];
+        send(&mut env.svm, ixs, &borrower, &[&borrower]).expect("token and SOL loans should succeed");
+
+        let fee = amount * FEE_BPS as u64 / 10_000;
+        let sol_fee = sol_amount * FEE_BPS as u64 / 10_000;
+        assert_eq!(token_balance(&env.svm, &env.vault), vault_before + fee);
+        assert_eq!(lamports(&env.svm, &sol_vault_pda()), sol_vault_before + sol_fee);
+        assert_eq!(lamports(&env.svm, &loan_pda(&env)), 0, "Token loan receipt should be closed");
+        let sol_loan = Pubkey::find_program_address(&[LOAN_SEED, sol_pool_pda().as_ref(), key(&borrower).as_ref()], &ID).0;
+        assert_eq!(lamports(&env.svm, &sol_loan), 0, "SOL loan receipt should be closed");
+
+        println!("✅ Token and SOL loans test passed");
+    }
+
+    /// Test two loans on the same vault, the inner one settled first
+    #[test]
+    fn test_nested_loans_same_vault() {
+        println!("🚀 Testing Nested Loans on the Same Vault");
+        let Some(mut env) = setup(anchor_spl::token::ID) else { return };
+
+        // A borrower has a single receipt per pool, so the inner loan goes to a second borrower
+        let outer = env.borrower.insecure_clone();
+        let inner = add_borrower(&mut env);
+        let (outer_amount, inner_amount) = (100_000_000, 50_000_000);
+        let vault_before = token_balance(&env.svm, &env.vault);
+
+        let ixs = vec![
+            borrow_ix_by(&env, &key(&outer), outer_amount),
+            borrow_ix_by(&env, &key(&inner), inner_amount),
+            repay_ix_by(&env, &key(&inner)),
+            repay_ix_by(&env, &key(&outer)),
+        ];
+        send(&mut env.svm, ixs, &outer, &[&outer, &inner]).expect("nested loans should succeed");
+
+        let outer_fee = outer_amount * FEE_BPS as u64 / 10_000;
+        let inner_fee = inner_amount * FEE_BPS as u64 / 10_000;
+        assert_eq!(token_balance(&env.svm, &env.vault), vault_before + outer_fee + inner_fee);
+        assert_eq!(token_balance(&env.svm, &ata(&key(&outer), &env.mint, &env.token_program)), BORROWER_FUNDS - outer_fee);
+        assert_eq!(token_balance(&env.svm, &ata(&key(&inner), &env.mint, &env.token_program)), BORROWER_FUNDS - inner_fee);
+
+        let pool_account = env.svm.get_account(&address(&env.pool)).unwrap();
+        let pool = Pool::try_deserialize(&mut pool_account.data.as_slice()).unwrap();
+        assert_eq!(pool.outstanding, 0);
+        assert_eq!(pool.loan_count, 2);
+
+        println!("✅ Nested loans test passed");
+    }
+
+    /// Test that crossed loans on the same vault are rejected
+    #[test]
+    fn test_crossed_loans_same_vault() {
+        println!("🚀 Testing Crossed Loans on the Same Vault");
+        let Some(mut env) = setup(anchor_spl::token::ID) else { return };
+
+        let first = env.borrower.insecure_clone();
+        let second = add_borrower(&mut env);
+        let vault_before = token_balance(&env.svm, &env.vault);
+
+        // The first repay settles the latest open borrow on the vault, pairing the
+        // first borrow with the repay of the second borrower
+        let ixs = vec![
+            borrow_ix_by(&env, &key(&first), 100_000_000),
+            borrow_ix_by(&env, &key(&second), 50_000_000),
+            repay_ix_by(&env, &key(&first)),
+            repay_ix_by(&env, &key(&second)),
+        ];
+        assert_protocol_error(send(&mut env.svm, ixs, &first, &[&first, &second]), 0, ProtocolError::InvalidBorrowerAta);
+        assert_eq!(token_balance(&env.svm, &env.vault), vault_before);
+
+        println!("✅ Crossed loans test passed");
+    }
+
+    /// Test the loan receipt: one open loan per borrower and pool, settled at its repay index
+    ///
+    /// A top-level repay cannot miss the index its borrow recorded: the borrow pins
+    /// the paired repay to its borrower ATA, so to its receipt. Only a program
+    /// repaying from another top-level instruction can, which `repay` rejects.
+    #[test]
+    fn test_loan_receipt() {
+        println!("🚀 Testing Loan Receipt");
+        let Some(mut env) = setup(anchor_spl::token::ID) else { return };
+
+        let borrower = env.borrower.insecure_clone();
+        let loan = loan_pda(&env);
+        let rent = env.svm.minimum_balance_for_rent_exemption(8 + LoanReceipt::INIT_SPACE);
+        let lamports_before = lamports(&env.svm, &key(&borrower));
+
+        // The borrow pays for the receipt and the repay closes it, refunding its rent
+        let ixs = vec![borrow_ix(&env, 1_000_000), repay_ix(&env)];
+        send(&mut env.svm, ixs, &borrower, &[&borrower]).expect("flash loan should succeed");
+        assert!(env.svm.get_account(&address(&loan)).is_none_or(|account| account.lamports == 0), "Loan receipt should be closed");
+        assert_eq!(
+            lamports(&env.svm, &key(&borrower)),
+            lamports_before - 5_000,
+            "The {rent} lamports of the receipt should be refunded, leaving only the signature fee"
+        );
+
+        // A second borrow while the receipt is open fails
+        let vault_before = token_balance(&env.svm, &env.vault);
+        let ixs = vec![borrow_ix(&env, 2_000_000), borrow_ix(&env, 3_000_000), repay_ix(&env), repay_ix(&env)];
+        assert_protocol_error(send(&mut env.svm, ixs, &borrower, &[&borrower]), 1, ProtocolError::LoanInProgress);
+        assert_eq!(token_balance(&env.svm, &env.vault), vault_before);
+
+        // The same goes for SOL loans
+        let Some(mut sol) = setup_sol() else { return };
+        let sol_borrower = key(&sol.borrower);
+        let ixs = vec![
+            borrow_sol_ix(&sol_borrower, 2_000_000),
+            borrow_sol_ix(&sol_borrower, 3_000_000),
+            repay_sol_ix(&sol_borrower),
+            repay_sol_ix(&sol_borrower),
+        ];
+        let signer = sol.borrower.insecure_clone();
+        assert_protocol_error(send(&mut sol.svm, ixs, &signer, &[&signer]), 1, ProtocolError::LoanInProgress);
+
+        // A CPI loan recorded at index 0 cannot be repaid by its caller at index 2
+        let Some(()) = add_cpi_borrower(&mut env, true) else { return };
+        let transfer = anchor_lang::solana_program::system_instruction::transfer(&key(&borrower), &Pubkey::new_unique(), 1_000_000);
+        let ixs = vec![cpi_borrow_ix(&env, 4_000_000, false), transfer, cpi_repay_ix(&env), check_repaid_ix(&[loan])];
+        assert_protocol_error(send(&mut env.svm, ixs, &borrower, &[&borrower]), 2, ProtocolError::RepayIndexMismatch);
+        assert_eq!(token_balance(&env.svm, &env.vault), vault_before);
+        assert_eq!(lamports(&env.svm, &loan), 0);
+
+        println!("✅ Loan receipt test passed");
+    }
+}
@@ -0,0 +1,138 @@
+#![allow(unexpected_cfgs)]
+#![allow(deprecated)]
+//! Test fixture borrowing from the flash loan program through CPIs
+//!
+//! `borrow_and_repay` borrows for the wallet signing it and, unless told not
+//! to, repays from the same instruction. `pda_borrow_and_repay` does the same
+//! for the `borrower` PDA of this program, and `repay` only repays, so tests can
+//! settle a loan from another instruction. It has to be an allowed CPI caller of
+//! the flash loan program.
+use anchor_lang::prelude::*;
+use blueshift_anchor_flash_loan::{cpi, program::BlueshiftAnchorFlashLoan};
+
+declare_id!("44444444444444444444444444444444444444444444");
+
+/// Seed of the PDA borrowing in `pda_borrow_and_repay`
+pub const BORROWER_SEED: &[u8] = b"borrower";
+
+#[program]
+pub mod cpi_borrower {
+  use super::*;
+
+  pub fn borrow_and_repay(ctx: Context<BorrowAndRepay>, amount: u64, repay: bool) -> Result<()> {
+    // The wallet signature is forwarded to the loan instructions
+    let borrower = ctx.accounts.borrower.to_account_info();
+
+    borrow_cpi(&ctx.accounts.loan_accounts, borrower.clone(), &[], amount)?;
+
+    if repay {
+        repay_cpi(&ctx.accounts.loan_accounts, borrower, &[])?;
+    }
+
+    Ok(())
+  }
+
+  pub fn pda_borrow_and_repay(ctx: Context<PdaBorrowAndRepay>, amount: u64, repay: bool) -> Result<()> {
+    // The PDA signs the loan instructions, and pays for the receipt
+    let signer_seeds: &[&[&[u8]]] = &[&[BORROWER_SEED, &[ctx.bumps.borrower]]];
+    let borrower = ctx.accounts.borrower.to_account_info();
+
+    borrow_cpi(&ctx.accounts.loan_accounts, borrower.clone(), signer_seeds, amount)?;
+
+    if repay {
+        repay_cpi(&ctx.accounts.loan_accounts, borrower, signer_seeds)?;
+    }
+
+    Ok(())
+  }
+
+  pub fn repay(ctx: Context<BorrowAndRepay>) -> Result<()> {
+    repay_cpi(&ctx.accounts.loan_accounts, ctx.accounts.borrower.to_account_info(), &[])
+  }
+}
+
+fn borrow_cpi<'info>(
+  accounts: &LoanAccounts<'info>,
+  borrower: AccountInfo<'info>,
+  signer_seeds: &[&[&[u8]]],
+  amount: u64,
+) -> Result<()> {
+  cpi::borrow(
+      CpiContext::new_with_signer(accounts.flash_loan_program.to_account_info(), cpi::accounts::Borrow {
+          borrower,
+          pool: accounts.pool.to_account_info(),
+          mint: accounts.mint.to_account_info(),
+          borrower_ata: accounts.borrower_ata.to_account_info(),
+          vault: accounts.vault.to_account_info(),
+          config: accounts.config.to_account_info(),
+          loan: accounts.loan.to_account_info(),
+          instructions: accounts.instructions.to_account_info(),
+          token_program: accounts.token_program.to_account_info(),
+          associated_token_program: accounts.associated_token_program.to_account_info(),
+          system_program: accounts.system_program.to_account_info(),
+      }, signer_seeds),
+      amount
+  )
+}
+
+fn repay_cpi<'info>(accounts: &LoanAccounts<'info>, borrower: AccountInfo<'info>, signer_seeds: &[&[&[u8]]]) -> Result<()> {
+  cpi::repay(CpiContext::new_with_signer(accounts.flash_loan_program.to_account_info(), cpi::accounts::Repay {
+      borrower,
+      pool: accounts.pool.to_account_info(),
+      mint: accounts.mint.to_account_info(),
+      borrower_ata: accounts.borrower_ata.to_account_info(),
+      vault: accounts.vault.to_account_info(),
+      config: accounts.config.to_account_info(),
+      loan: accounts.loan.to_account_info(),
+      instructions: accounts.instructions.to_account_info(),
+      token_program: accounts.token_program.to_account_info(),
+  }, signer_seeds))
+}
+
+#[derive(Accounts)]
+pub struct BorrowAndRepay<'info> {
+  #[account(mut)]
+  pub borrower: Signer<'info>,
+  pub loan_accounts: LoanAccounts<'info>,
+}
+
+#[derive(Accounts)]
+pub struct PdaBorrowAndRepay<'info> {
+  /// CHECK: PDA of this program borrowing, a system account funded by the tests
+  #[account(
+    mut,
+    seeds = [BORROWER_SEED],
+    bump,
+  )]
+  pub borrower: UncheckedAccount<'info>,
+  pub loan_accounts: LoanAccounts<'info>,
+}
+
+/// The loan accounts are checked by the flash loan program
+#[derive(Accounts)]
+pub struct LoanAccounts<'info> {
+  /// CHECK: Pool of the loan
+  #[account(mut)]
+  pub pool: UncheckedAccount<'info>,
+  /// CHECK: Lent mint
+  pub mint: UncheckedAccount<'info>,
+  /// CHECK: Borrower ATA, created by the borrow if needed
+  #[account(mut)]
+  pub borrower_ata: UncheckedAccount<'info>,
+  /// CHECK: Pool vault
+  #[account(mut)]
+  pub vault: UncheckedAccount<'info>,
+  /// CHECK: Protocol config
+  pub config: UncheckedAccount<'info>,
+  /// CHECK: Loan receipt of the borrower
+  #[account(mut)]
+  pub loan: UncheckedAccount<'info>,
+  /// CHECK: InstructionsSysvar account
+  pub instructions: UncheckedAccount<'info>,
+  /// CHECK: Token program of the mint
+  pub token_program: UncheckedAccount<'info>,
+  /// CHECK: Associated token program
+  pub associated_token_program: UncheckedAccount<'info>,
+  pub system_program: Program<'info, System>,
+  pub flash_loan_program: Program<'info, BlueshiftAnchorFlashLoan>,
+}
@@ -0,0 +1,83 @@
+#![allow(unexpected_cfgs)]
+#![allow(deprecated)]
+//! Test fixture implementing the `on_flash_loan` callback of `flash_loan`
+//!
+//! The lent funds land on a token account owned by the `authority` PDA, which
+//! signs their way back to the pool vault. The callback sends back
+//! `amount + fee`, or the little-endian `u64` of the callback data when it is 8
+//! bytes long, so tests can under-repay on purpose.
+use anchor_lang::prelude::*;
+use anchor_spl::token_interface::{TokenInterface, TokenAccount, Mint, TransferChecked, transfer_checked};
+
+declare_id!("33333333333333333333333333333333333333333333");
+
+/// Seed of the PDA owning the receiver token account
+pub const AUTHORITY_SEED: &[u8] = b"authority";
+
+#[program]
+pub mod flash_loan_receiver {
+  use super::*;
+
+  pub fn on_flash_loan(
+    ctx: Context<OnFlashLoan>,
+    initiator: Pubkey,
+    mint: Pubkey,
+    amount: u64,
+    fee: u64,
+    data: Vec<u8>,
+  ) -> Result<()> {
+    // The arguments have to describe the accounts the loan was made with
+    require_keys_eq!(initiator, ctx.accounts.initiator.key());
+    require_keys_eq!(mint, ctx.accounts.mint.key());
+
+    let repay_amount = match <[u8; 8]>::try_from(data.as_slice()) {
+        Ok(bytes) => u64::from_le_bytes(bytes),
+        Err(_) => amount.saturating_add(fee),
+    };
+
+    let signer_seeds: &[&[&[u8]]] = &[&[AUTHORITY_SEED, &[ctx.bumps.authority]]];
+
+    // Send the funds back to the pool vault
+    transfer_checked(
+        CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            TransferChecked {
+                from: ctx.accounts.receiver_token_account.to_account_info(),
+                mint: ctx.accounts.mint.to_account_info(),
+                to: ctx.accounts.vault.to_account_info(),
+                authority: ctx.accounts.authority.to_account_info(),
+            },
+            signer_seeds
+        ),
+        repay_amount,
+        ctx.accounts.mint.decimals
+    )
+  }
+}
+
+/// Accounts `flash_loan` calls the receiver with, `authority` being its first remaining account
+#[derive(Accounts)]
+pub struct OnFlashLoan<'info> {
+  pub initiator: Signer<'info>,
+  pub mint: InterfaceAccount<'info, Mint>,
+  #[account(
+    mut,
+    token::mint = mint,
+    token::token_program = token_program,
+  )]
+  pub vault: InterfaceAccount<'info, TokenAccount>,
+  #[account(
+    mut,
+    token::mint = mint,
+    token::authority = authority,
+    token::token_program = token_program,
+  )]
+  pub receiver_token_account: InterfaceAccount<'info, TokenAccount>,
+  pub token_program: Interface<'info, TokenInterface>,
+  /// CHECK: PDA owning the receiver token account, only used as a signer
+  #[account(
+    seeds = [AUTHORITY_SEED],
+    bump,
+  )]
+  pub authority: UncheckedAccount<'info>,
+}